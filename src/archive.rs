@@ -0,0 +1,293 @@
+//! Processing of CSV files inside ZIP (`zip` feature) and TAR (`tar`
+//! feature, may be compressed) archives. Each member is sniffed with
+//! its own `CsvSniffer` and converted to its own ASV file, archive is
+//! read twice for that, so members are never fully in memory.
+//!
+//! Manifest of conversion is an ASV file itself, with columns
+//! `member`, `output` and `status`.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use crate::{decompress, ControlBytePolicy, CsvSniffer, Dialect, NormalizeOptions, NormalizeReport};
+use crate::dialects::control_bytes::ControlByteGuard;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ArchiveFormat {
+    Zip,
+    /// Possibly compressed, see `Compression`
+    Tar,
+}
+
+/// Sniffed archive member
+#[derive(Clone, Debug)]
+pub struct ArchiveMember {
+    pub path: String,
+    /// Best dialect, `None` if no valid dialects were found
+    pub dialect: Option<Dialect>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveManifest {
+    pub converted: Vec<ConvertedMember>,
+    /// Members without valid dialect
+    pub skipped: Vec<String>,
+    /// Applied to member names and outputs written by `write_to`
+    pub control_bytes: ControlBytePolicy,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConvertedMember {
+    pub path: String,
+    pub output: PathBuf,
+    pub report: NormalizeReport,
+}
+
+impl ArchiveFormat {
+    /// Detects archive by the first bytes of (decompressed) file
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Some(Self::Zip)
+        } else if head.get(257..262) == Some(b"ustar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn of_file(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let mut head = vec![];
+        decompress::decoder(File::open(path)?)?
+            .take(512)
+            .read_to_end(&mut head)?;
+
+        Ok(Self::detect(&head))
+    }
+}
+
+/// Sniffs every regular file in archive
+pub fn sniff(path: impl AsRef<Path>, has_headers: Option<bool>) -> io::Result<Vec<ArchiveMember>> {
+    let mut members = vec![];
+    for_each_member(path.as_ref(), |name, mut reader| {
+        let mut sniffer = CsvSniffer::new(has_headers);
+        sniffer.try_process(&mut reader)?;
+
+        members.push(ArchiveMember {
+            path: name.to_string(),
+            dialect: sniffer.dialects().into_iter().max(),
+        });
+        Ok(())
+    })?;
+
+    Ok(members)
+}
+
+/// Converts every regular file with valid dialect into
+/// `<out_dir>/<member path>.asv`, returns manifest of conversion.
+/// Members with the same output path (`x.csv`, `./x.csv`, `../x.csv`)
+/// get a number before extension: `x.csv.1.asv`, `x.csv.2.asv`, ...
+pub fn convert(
+    path: impl AsRef<Path>,
+    has_headers: Option<bool>,
+    options: &NormalizeOptions,
+    out_dir: impl AsRef<Path>,
+) -> io::Result<ArchiveManifest> {
+    let members = sniff(path.as_ref(), has_headers)?;
+
+    let mut manifest = ArchiveManifest {
+        control_bytes: options.control_bytes,
+        ..Default::default()
+    };
+    let mut outputs = HashSet::new();
+    let mut members = members.into_iter();
+    for_each_member(path.as_ref(), |name, reader| {
+        // archive is read in the same order
+        let member = members.next()
+            .filter(|member| member.path == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Archive changed while it's processed"))?;
+
+        let Some(dialect) = member.dialect else {
+            manifest.skipped.push(member.path);
+            return Ok(())
+        };
+
+        let output = (0..)
+            .map(|n| output_path(out_dir.as_ref(), name, n))
+            .find(|output| !outputs.contains(output))
+            .expect("Numbers are not exhausted");
+        outputs.insert(output.clone());
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let writer = BufWriter::new(File::create(&output)?);
+        let report = dialect.to_asv_with_options(reader, writer, options)?;
+        manifest.converted.push(ConvertedMember {
+            path: member.path,
+            output,
+            report,
+        });
+        Ok(())
+    })?;
+
+    Ok(manifest)
+}
+
+impl ArchiveManifest {
+    /// Writes manifest as ASV, skipped members have empty output
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let converted = self.converted
+            .iter()
+            .map(|x| (x.path.as_str(), x.output.to_string_lossy(), "converted"));
+        let skipped = self.skipped
+            .iter()
+            .map(|x| (x.as_str(), "".into(), "no dialect"));
+
+        let mut guard = ControlByteGuard::new(self.control_bytes);
+        write_manifest_row(&mut writer, &mut guard, ["member", "output", "status"])?;
+        for (member, output, status) in converted.chain(skipped) {
+            write_manifest_row(&mut writer, &mut guard, [member, &output, status])?;
+        }
+
+        writer.flush()
+    }
+}
+
+/// Member names may contain `0x1f`/`0x1e` too
+fn write_manifest_row(writer: &mut impl Write, guard: &mut ControlByteGuard, row: [&str; 3]) -> io::Result<()> {
+    for (i, value) in row.iter().enumerate() {
+        if i != 0 {
+            guard.separator(writer)?;
+        }
+        guard.write(writer, value.as_bytes())?;
+        guard.end_cell();
+    }
+    guard.end_row(writer)
+}
+
+/// Member path inside `out_dir`, absolute and parent components are
+/// dropped, `n`-th path of the same member gets number `n` (from 1).
+/// Member without other components (e.g. `..`) is named `unnamed`.
+fn output_path(out_dir: &Path, member: &str, n: usize) -> PathBuf {
+    let mut output = out_dir.to_path_buf();
+    for component in Path::new(member).components() {
+        if let Component::Normal(part) = component {
+            output.push(part);
+        }
+    }
+    if output == out_dir {
+        output.push("unnamed");
+    }
+
+    let mut output = output.into_os_string();
+    if n > 0 {
+        output.push(format!(".{n}"));
+    }
+    output.push(".asv");
+    PathBuf::from(output)
+}
+
+/// Calls `f` with name and reader of every regular file in archive
+fn for_each_member(
+    path: &Path,
+    mut f: impl FnMut(&str, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    match ArchiveFormat::of_file(path)? {
+        #[cfg(feature = "zip")]
+        Some(ArchiveFormat::Zip) => {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut member = archive.by_index(i)?;
+                if !member.is_file() {
+                    continue
+                }
+
+                let name = member.name().to_string();
+                f(&name, &mut member)?;
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tar")]
+        Some(ArchiveFormat::Tar) => {
+            let mut archive = tar::Archive::new(decompress::decoder(File::open(path)?)?);
+            for member in archive.entries()? {
+                let mut member = member?;
+                if !member.header().entry_type().is_file() {
+                    continue
+                }
+
+                let name = member.path()?.to_string_lossy().into_owned();
+                f(&name, &mut member)?;
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a supported archive")),
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "tar")]
+mod tests {
+    use std::fs;
+    use crate::{ControlBytePolicy, NormalizeOptions};
+    use super::convert;
+
+    /// Member names are written as is, `tar::Builder` would reject `..`
+    fn tar(members: &[&str]) -> Vec<u8> {
+        let data = b"id,name\n1,ann\n2,bob\n3,carl\n4,dan\n5,eve\n";
+        let mut builder = tar::Builder::new(vec![]);
+        for name in members {
+            let mut header = tar::Header::new_ustar();
+            header.as_ustar_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, &data[..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn same_output_paths_are_numbered() {
+        let dir = std::env::temp_dir().join(format!("brutal-csv-archive-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("members.tar");
+        fs::write(&path, tar(&["x.csv", "./x.csv", "../x.csv", "a\x1fb.csv"])).unwrap();
+
+        let options = NormalizeOptions { control_bytes: ControlBytePolicy::Replace(b'_'), ..Default::default() };
+        let manifest = convert(&path, None, &options, dir.join("out")).unwrap();
+        let mut written = vec![];
+        manifest.write_to(&mut written).unwrap();
+        let outputs: Vec<_> = manifest.converted
+            .iter()
+            .map(|member| member.output.strip_prefix(&dir).unwrap().to_str().unwrap())
+            .collect();
+        let are_written = manifest.converted.iter().all(|member| member.output.is_file());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(outputs, ["out/x.csv.asv", "out/x.csv.1.asv", "out/x.csv.2.asv", "out/a\x1fb.csv.asv"]);
+        assert!(are_written);
+        assert!(String::from_utf8(written).unwrap().contains("a_b.csv\x1f"));
+    }
+
+    #[test]
+    fn member_without_name_stays_inside_out_dir() {
+        let dir = std::env::temp_dir().join(format!("brutal-csv-archive-unnamed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("members.tar");
+        fs::write(&path, tar(&["..", ".", "unnamed"])).unwrap();
+
+        let manifest = convert(&path, None, &NormalizeOptions::default(), dir.join("out")).unwrap();
+        let outputs: Vec<_> = manifest.converted
+            .iter()
+            .map(|member| member.output.strip_prefix(&dir).unwrap().to_str().unwrap().to_string())
+            .collect();
+        let is_outside_written = dir.join("out.asv").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(outputs, ["out/unnamed.asv", "out/unnamed.1.asv", "out/unnamed.2.asv"]);
+        assert!(!is_outside_written);
+    }
+}
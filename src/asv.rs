@@ -0,0 +1,290 @@
+//! Reader for ASV files, as produced by `Dialect::to_asv`:
+//!   - header row is always present, `__NO_HEADER__` is used
+//!     as a placeholder if original file did not contain header
+//!   - field delimiter is `0x1f` (`UNIT SEPARATOR`)
+//!   - row terminator is `0x1e` (`RECORD SEPARATOR`)
+//!   - no escaping or quoting, parsing is simply splitting
+//!
+//! Escaped variant (`ControlBytePolicy::Escape`) additionally
+//! prepends `0x1b` (`ESCAPE`) to every `0x1b`, `0x1f` and `0x1e`
+//! byte inside values.
+
+use std::io::{BufRead, BufReader, Read, Result};
+
+pub const FIELD_SEPARATOR: u8 = 0x1f;
+pub const RECORD_SEPARATOR: u8 = 0x1e;
+pub const ESCAPE: u8 = 0x1b;
+pub const NO_HEADER: &str = "__NO_HEADER__";
+
+pub struct AsvReader<R: Read> {
+    reader: BufReader<R>,
+    header: Option<AsvRecord>,
+    escaped: bool,
+    raw: Vec<u8>,
+}
+
+/// Single ASV record, fields are stored in one contiguous buffer.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AsvRecord {
+    data: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl<R: Read> AsvReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::with_capacity(1024*1024, reader), // 1 MiB
+            header: None,
+            escaped: false,
+            raw: vec![],
+        }
+    }
+
+    /// Reader for escaped ASV variant
+    pub fn new_escaped(reader: R) -> Self {
+        Self {
+            escaped: true,
+            ..Self::new(reader)
+        }
+    }
+
+    /// Returns column names, or `None` if file has only
+    /// `__NO_HEADER__` placeholders in header row.
+    pub fn headers(&mut self) -> Result<Option<Vec<String>>> {
+        let header = self.read_header()?;
+        if header.iter().all(|x| x == NO_HEADER.as_bytes()) {
+            return Ok(None)
+        }
+
+        Ok(Some(header
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect()
+        ))
+    }
+
+    /// Reads next data record into `record`, reusing its allocations.
+    /// Returns `false` when there are no records left.
+    pub fn read_record(&mut self, record: &mut AsvRecord) -> Result<bool> {
+        self.read_header()?;
+        self.read_raw_record(record)
+    }
+
+    pub fn records(&mut self) -> AsvRecords<'_, R> {
+        AsvRecords { reader: self }
+    }
+
+    fn read_header(&mut self) -> Result<&AsvRecord> {
+        if self.header.is_none() {
+            let mut header = AsvRecord::default();
+            self.read_raw_record(&mut header)?;
+            self.header = Some(header);
+        }
+
+        Ok(self.header.as_ref().unwrap())
+    }
+
+    #[inline]
+    fn read_raw_record(&mut self, record: &mut AsvRecord) -> Result<bool> {
+        record.data.clear();
+        record.ends.clear();
+
+        if self.escaped {
+            return self.read_escaped_record(record)
+        }
+
+        let size = self.reader.read_until(RECORD_SEPARATOR, &mut record.data)?;
+        if size == 0 {
+            return Ok(false)
+        }
+
+        // last record may have no terminator
+        if record.data.last() == Some(&RECORD_SEPARATOR) {
+            record.data.pop();
+        }
+
+        for (pos, c) in record.data.iter().enumerate() {
+            if *c == FIELD_SEPARATOR {
+                record.ends.push(pos);
+            }
+        }
+        record.ends.push(record.data.len());
+
+        Ok(true)
+    }
+
+    fn read_escaped_record(&mut self, record: &mut AsvRecord) -> Result<bool> {
+        self.raw.clear();
+        loop {
+            let size = self.reader.read_until(RECORD_SEPARATOR, &mut self.raw)?;
+            if size == 0 || !is_escaped_terminator(&self.raw) {
+                break
+            }
+        }
+
+        if self.raw.is_empty() {
+            return Ok(false)
+        }
+
+        let mut escape_active = false;
+        for c in &self.raw {
+            if escape_active {
+                escape_active = false;
+                record.data.push(*c);
+            } else if *c == ESCAPE {
+                escape_active = true;
+            } else if *c == FIELD_SEPARATOR {
+                // keep layout same as in unescaped records
+                record.ends.push(record.data.len());
+                record.data.push(*c);
+            } else if *c != RECORD_SEPARATOR {
+                record.data.push(*c);
+            }
+        }
+        record.ends.push(record.data.len());
+
+        Ok(true)
+    }
+}
+
+/// Record separator at the end of buffer is preceded by odd number of escapes
+fn is_escaped_terminator(buffer: &[u8]) -> bool {
+    if buffer.last() != Some(&RECORD_SEPARATOR) {
+        return false
+    }
+
+    let escapes = buffer[..buffer.len() - 1]
+        .iter()
+        .rev()
+        .take_while(|c| **c == ESCAPE)
+        .count();
+    escapes % 2 == 1
+}
+
+impl AsvRecord {
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&[u8]> {
+        let end = *self.ends.get(i)?;
+        let start = if i == 0 { 0 } else { self.ends[i - 1] + 1 };
+        Some(&self.data[start..end])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.len()).map(|i| self.get(i).unwrap())
+    }
+
+    #[inline]
+    pub(crate) fn push_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    #[inline]
+    pub(crate) fn end_field(&mut self) {
+        self.ends.push(self.data.len());
+        self.data.push(FIELD_SEPARATOR);
+    }
+
+    /// Ends the last field, or drops it if it's the empty
+    /// one after trailing field separator
+    #[inline]
+    pub(crate) fn end_record(&mut self, has_trailing_separator: bool) {
+        let last_is_empty = self.ends.last().is_some_and(|end| end + 1 == self.data.len());
+        if has_trailing_separator && last_is_empty {
+            self.data.pop();
+        } else {
+            self.ends.push(self.data.len());
+        }
+    }
+}
+
+pub struct AsvRecords<'a, R: Read> {
+    reader: &'a mut AsvReader<R>,
+}
+
+impl<R: Read> Iterator for AsvRecords<'_, R> {
+    type Item = Result<AsvRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = AsvRecord::default();
+        match self.reader.read_record(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(reader: &mut AsvReader<&[u8]>) -> Vec<Vec<Vec<u8>>> {
+        reader.records()
+            .map(|record| record.unwrap().iter().map(<[u8]>::to_vec).collect())
+            .collect()
+    }
+
+    #[test]
+    fn no_header_placeholder() {
+        let mut reader = AsvReader::new(&b"__NO_HEADER__\x1f__NO_HEADER__\x1e1\x1f2\x1e"[..]);
+        assert_eq!(reader.headers().unwrap(), None);
+        assert_eq!(records(&mut reader), [vec![b"1".to_vec(), b"2".to_vec()]]);
+
+        // only the whole row of placeholders is not a header
+        let mut reader = AsvReader::new(&b"__NO_HEADER__\x1fb\x1e"[..]);
+        assert_eq!(reader.headers().unwrap(), Some(vec![NO_HEADER.into(), "b".into()]));
+    }
+
+    /// Header is read by the first `read_record` too
+    #[test]
+    fn records_without_headers_call() {
+        let mut reader = AsvReader::new(&b"a\x1fb\x1e1\x1f2\x1e3\x1f4\x1e"[..]);
+        let mut record = AsvRecord::default();
+        assert!(reader.read_record(&mut record).unwrap());
+        assert_eq!(record.iter().collect::<Vec<_>>(), [b"1", b"2"]);
+        assert!(reader.read_record(&mut record).unwrap());
+        assert_eq!(record.iter().collect::<Vec<_>>(), [b"3", b"4"]);
+        assert!(!reader.read_record(&mut record).unwrap());
+        assert_eq!(reader.headers().unwrap(), Some(vec!["a".into(), "b".into()]));
+    }
+
+    #[test]
+    fn empty_fields() {
+        let mut reader = AsvReader::new(&b"a\x1fb\x1e1\x1f\x1e\x1f\x1e"[..]);
+        assert_eq!(records(&mut reader), [vec![b"1".to_vec(), vec![]], vec![vec![], vec![]]]);
+    }
+
+    #[test]
+    fn missing_final_record_separator() {
+        let mut reader = AsvReader::new(&b"a\x1fb\x1e1\x1f2"[..]);
+        assert_eq!(records(&mut reader), [vec![b"1".to_vec(), b"2".to_vec()]]);
+
+        // escaped record separator at file end is a part of value
+        let mut reader = AsvReader::new_escaped(&b"a\x1fb\x1e1\x1f2\x1b\x1e"[..]);
+        assert_eq!(records(&mut reader), [vec![b"1".to_vec(), b"2\x1e".to_vec()]]);
+    }
+
+    #[test]
+    fn escaped_separators() {
+        let mut reader = AsvReader::new_escaped(&b"a\x1fb\x1e\x1b\x1b\x1f\x1b\x1f\x1b\x1e\x1e"[..]);
+        assert_eq!(records(&mut reader), [vec![b"\x1b".to_vec(), b"\x1f\x1e".to_vec()]]);
+    }
+
+    #[test]
+    fn get_out_of_range() {
+        let mut reader = AsvReader::new(&b"a\x1fb\x1e1\x1f2\x1e"[..]);
+        let record = reader.records().next().unwrap().unwrap();
+        assert_eq!((record.len(), record.get(1), record.get(2)), (2, Some(&b"2"[..]), None));
+
+        let empty = AsvRecord::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.get(0), None);
+    }
+}
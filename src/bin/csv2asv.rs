@@ -1,10 +1,12 @@
 //! Transform any CSV file into ASV file,
 //! dropping empty columns. 
 
-use std::io::{BufWriter, Seek};
+use std::io::{BufWriter, Seek, Write};
 use std::process::exit;
-use clap::{Parser};
+use clap::{Parser, ValueEnum};
 use clio::*;
+use brutal_csv::{asv, ControlBytePolicy, NormalizeOptions, QuarantineSink, Tolerance, Utf8Policy};
+use brutal_csv::asv::AsvRecord;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -19,13 +21,148 @@ struct Args {
 
     /// Output ASV file
     #[clap(short, long, value_parser)]
-    output: Output
+    output: Output,
+
+    /// What to do with 0x1f/0x1e bytes inside cell values
+    #[clap(long, value_enum, default_value_t = ControlBytes::PassThrough)]
+    control_bytes: ControlBytes,
+
+    /// What to do with cells which are not valid UTF-8
+    #[clap(long, value_enum, default_value_t = InvalidUtf8::PassThrough)]
+    invalid_utf8: InvalidUtf8,
+
+    /// Write comment lines into this file, they are dropped otherwise
+    #[clap(long, value_parser)]
+    comments: Option<Output>,
+
+    /// Write footer rows (totals, row count) into this ASV file,
+    /// they are dropped otherwise
+    #[clap(long, value_parser)]
+    footer: Option<Output>,
+
+    /// Tolerate up to N malformed rows (of different shape)
+    /// inside table, they are not converted
+    #[clap(long, value_parser, conflicts_with = "max_malformed_ppm")]
+    max_malformed: Option<usize>,
+
+    /// Tolerate malformed rows up to this share of rows,
+    /// in parts per million
+    #[clap(long, value_parser)]
+    max_malformed_ppm: Option<u32>,
+
+    /// Write source lines of tolerated malformed rows into this file,
+    /// they are dropped otherwise
+    #[clap(long, value_parser)]
+    quarantine: Option<Output>,
+
+    /// Save offset of every N-th record into `<input>.idx`,
+    /// see `brutal_csv::index::RecordIndex`
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    index_every: Option<u64>,
+
+    /// Input is ZIP or TAR archive: each member is converted into
+    /// `<member path>.asv` next to the output, which is the manifest
+    #[cfg(any(feature = "zip", feature = "tar"))]
+    #[clap(long)]
+    archive: bool,
+
+    /// Decode values from detected encoding into UTF-8
+    #[cfg(feature = "encoding")]
+    #[clap(long)]
+    to_utf8: bool,
+
+    /// Number of threads for dialect detection (0 = one per CPU)
+    #[cfg(feature = "parallel")]
+    #[clap(long, default_value_t = 1)]
+    threads: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ControlBytes {
+    /// Write as is, these values shift columns
+    PassThrough,
+    /// Stop with an error
+    Fail,
+    /// Drop these bytes
+    Strip,
+    /// Replace these bytes with space
+    Replace,
+    /// Write escaped ASV variant (0x1b before 0x1b, 0x1f and 0x1e)
+    Escape,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum InvalidUtf8 {
+    /// Write as is
+    PassThrough,
+    /// Replace invalid sequences with U+FFFD
+    Replace,
+    /// Write empty cell instead
+    DropCell,
+    /// Skip whole row
+    DropRow,
+    /// Write invalid bytes (and all backslashes) as \xNN
+    HexEscape,
+}
+
+impl From<InvalidUtf8> for Utf8Policy {
+    fn from(value: InvalidUtf8) -> Self {
+        match value {
+            InvalidUtf8::PassThrough => Utf8Policy::PassThrough,
+            InvalidUtf8::Replace => Utf8Policy::Replace,
+            InvalidUtf8::DropCell => Utf8Policy::DropCell,
+            InvalidUtf8::DropRow => Utf8Policy::DropRow,
+            InvalidUtf8::HexEscape => Utf8Policy::HexEscape,
+        }
+    }
+}
+
+impl From<ControlBytes> for ControlBytePolicy {
+    fn from(value: ControlBytes) -> Self {
+        match value {
+            ControlBytes::PassThrough => ControlBytePolicy::PassThrough,
+            ControlBytes::Fail => ControlBytePolicy::Fail,
+            ControlBytes::Strip => ControlBytePolicy::Strip,
+            ControlBytes::Replace => ControlBytePolicy::Replace(b' '),
+            ControlBytes::Escape => ControlBytePolicy::Escape,
+        }
+    }
+}
+
+
+/// Takes `--quarantine` output, which becomes the sink of malformed rows
+fn normalize_options(cli: &mut Args) -> NormalizeOptions {
+    #[cfg(feature = "encoding")]
+    let transcode_to_utf8 = cli.to_utf8;
+    #[cfg(not(feature = "encoding"))]
+    let transcode_to_utf8 = false;
+
+    NormalizeOptions {
+        control_bytes: cli.control_bytes.into(),
+        record_index: cli.index_every,
+        transcode_to_utf8,
+        invalid_utf8: cli.invalid_utf8.into(),
+        keep_comments: cli.comments.is_some(),
+        keep_footer: cli.footer.is_some(),
+        quarantine: cli.quarantine.take().map(|output| QuarantineSink::new(BufWriter::new(output))),
+    }
 }
 
+fn tolerance(cli: &Args) -> Tolerance {
+    match (cli.max_malformed, cli.max_malformed_ppm) {
+        (Some(records), _) => Tolerance::Records(records),
+        (_, Some(ppm)) => Tolerance::PartsPerMillion(ppm),
+        _ => Tolerance::Strict,
+    }
+}
 
 #[allow(unused_mut)]
 fn main() {
     let mut cli = Args::parse();
+    if cli.index_every.is_some() && !cli.input.is_local() {
+        eprintln!("Record index can only be saved next to local input file");
+        exit(1);
+    }
 
     #[cfg(feature = "progress")]
     let progress = if let Some(len) = cli.input.len() {
@@ -34,28 +171,133 @@ fn main() {
         indicatif::ProgressBar::new_spinner()
     };
 
-    let mut detector = brutal_csv::CsvSniffer::new(cli.headers);
-    let mut reader = cli.input.clone();
+    // local files are memory-mapped, except when progress
+    // is tracked, as it's done by wrapping reader
+    #[cfg(not(feature = "progress"))]
+    let path = cli.input.is_local().then(|| cli.input.path().to_path_buf());
     #[cfg(feature = "progress")]
-    let mut reader = progress.wrap_read(reader);
+    let path: Option<std::path::PathBuf> = None;
 
-    detector.process(&mut reader);
-    let dialects = detector.dialects();
+    #[cfg(any(feature = "zip", feature = "tar"))]
+    if cli.archive {
+        convert_archive(cli);
+        return
+    }
 
-    if let Some(dialect) = dialects.iter().max() {
-        cli.input.rewind().expect("To transform we need two full passes over stream, so it must be rewindable, so pipes don't work.");
+    let mut detector = brutal_csv::CsvSniffer::new(cli.headers).with_tolerance(tolerance(&cli));
+    #[cfg(feature = "parallel")]
+    if cli.threads != 1 {
+        detector = detector.with_threads(cli.threads).expect("Failed to start sniffing threads");
+    }
 
+    if let Some(path) = &path {
+        detector.process_path(path).expect("Failed to read input file");
+    } else {
+        let mut reader = cli.input.clone();
         #[cfg(feature = "progress")]
-        progress.reset();
+        let mut reader = progress.wrap_read(reader);
 
+        detector.process(&mut reader);
+    }
+    let dialects = detector.dialects();
+
+    if let Some(dialect) = dialects.iter().max() {
         eprintln!("{:#?}", dialect);
 
-        let reader = cli.input;
-        #[cfg(feature = "progress")]
-        let mut reader = progress.wrap_read(reader);
-        dialect.to_asv(reader, BufWriter::new(cli.output));
+        let options = normalize_options(&mut cli);
+        let index_path = brutal_csv::index::RecordIndex::sidecar_path(cli.input.path().path());
+        let writer = BufWriter::new(cli.output);
+
+        let result = match &path {
+            #[cfg(feature = "parallel")]
+            Some(path) if cli.threads != 1 => {
+                dialect.to_asv_path_parallel(path, writer, &options, cli.threads)
+            }
+            Some(path) => dialect.to_asv_path(path, writer, &options),
+            None => {
+                cli.input.rewind().expect("To transform we need two full passes over stream, so it must be rewindable, so pipes don't work.");
+
+                #[cfg(feature = "progress")]
+                progress.reset();
+
+                let reader = cli.input;
+                #[cfg(feature = "progress")]
+                let mut reader = progress.wrap_read(reader);
+                dialect.to_asv_with_options(reader, writer, &options)
+            }
+        };
+
+        match result {
+            Ok(mut report) => {
+                if let Some(index) = report.record_index.take() {
+                    index.save(&index_path).expect("Failed to save record index");
+                }
+                if let Some(output) = cli.comments {
+                    write_lines(output, &std::mem::take(&mut report.comments))
+                        .expect("Failed to write comments");
+                }
+                if let Some(output) = cli.footer {
+                    write_records(output, &std::mem::take(&mut report.footer))
+                        .expect("Failed to write footer");
+                }
+                eprintln!("{:#?}", report)
+            }
+            Err(e) => {
+                eprintln!("Conversion failed: {}", e);
+                exit(1);
+            }
+        }
     } else {
         eprintln!("No valid dialects found");
         exit(1);
     }
 }
+
+/// Writes side channel lines, LF terminated
+fn write_lines(output: Output, lines: &[Vec<u8>]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(output);
+    for line in lines {
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+/// Writes side channel records as ASV
+fn write_records(output: Output, records: &[AsvRecord]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(output);
+    for record in records {
+        for (i, value) in record.iter().enumerate() {
+            if i != 0 {
+                writer.write_all(&[asv::FIELD_SEPARATOR])?;
+            }
+            writer.write_all(value)?;
+        }
+        writer.write_all(&[asv::RECORD_SEPARATOR])?;
+    }
+    writer.flush()
+}
+
+#[cfg(any(feature = "zip", feature = "tar"))]
+fn convert_archive(mut cli: Args) {
+    if !cli.input.is_local() || !cli.output.is_local() {
+        eprintln!("Archive mode requires local input and output files");
+        exit(1);
+    }
+
+    let options = normalize_options(&mut cli);
+    let out_dir = cli.output.path().parent().unwrap_or(std::path::Path::new("."));
+
+    match brutal_csv::archive::convert(cli.input.path().path(), cli.headers, &options, out_dir) {
+        Ok(manifest) => {
+            for member in &manifest.skipped {
+                eprintln!("Skipped {}: no valid dialects found", member);
+            }
+            manifest.write_to(BufWriter::new(cli.output)).expect("Failed to write manifest");
+        }
+        Err(e) => {
+            eprintln!("Conversion failed: {}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Byte order mark at the start of the file. It's recorded on the
+//! dialect and skipped by sniffer, conversion and reader, so it never
+//! ends up in the first header name.
+
+use std::io::{self, Read};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Bom {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Bom {
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        [Self::Utf8, Self::Utf16Le, Self::Utf16Be]
+            .into_iter()
+            .find(|bom| head.starts_with(bom.bytes()))
+    }
+
+    pub fn bytes(self) -> &'static [u8] {
+        match self {
+            Self::Utf8 => &[0xef, 0xbb, 0xbf],
+            Self::Utf16Le => &[0xff, 0xfe],
+            Self::Utf16Be => &[0xfe, 0xff],
+        }
+    }
+
+    #[cfg(feature = "encoding")]
+    pub(crate) fn encoding(self) -> &'static encoding_rs::Encoding {
+        match self {
+            Self::Utf8 => encoding_rs::UTF_8,
+            Self::Utf16Le => encoding_rs::UTF_16LE,
+            Self::Utf16Be => encoding_rs::UTF_16BE,
+        }
+    }
+}
+
+/// Length of `bom`, 0 if there is none
+pub(crate) fn len(bom: Option<Bom>) -> usize {
+    bom.map_or(0, |bom| bom.bytes().len())
+}
+
+/// Skips `bom` at the start of `reader`, fails if file starts differently
+pub(crate) fn skip(reader: &mut impl Read, bom: Option<Bom>) -> io::Result<()> {
+    let Some(bom) = bom else {
+        return Ok(())
+    };
+
+    let mut head = [0; 3];
+    let head = &mut head[..bom.bytes().len()];
+    reader.read_exact(head).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than its BOM"),
+        _ => e,
+    })?;
+    if head != bom.bytes() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "File doesn't start with its BOM"))
+    }
+
+    Ok(())
+}
+
+/// `src` without `bom` at the start
+pub(crate) fn strip(src: &[u8], bom: Option<Bom>) -> &[u8] {
+    match bom {
+        Some(bom) if Bom::detect(src) == Some(bom) => &src[bom.bytes().len()..],
+        _ => src,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use super::{skip, Bom};
+
+    #[test]
+    fn skip_checks_bytes() {
+        let mut src = &b"\xef\xbb\xbfid"[..];
+        skip(&mut src, Some(Bom::Utf8)).unwrap();
+        assert_eq!(src, b"id");
+
+        let mut src = &b"id,name"[..];
+        skip(&mut src, None).unwrap();
+        assert_eq!(src, b"id,name");
+
+        assert_eq!(skip(&mut &b"id,name"[..], Some(Bom::Utf8)).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(skip(&mut &b"\xff"[..], Some(Bom::Utf16Le)).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
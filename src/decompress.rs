@@ -0,0 +1,124 @@
+//! Transparent decompression of inputs, compression is detected by
+//! magic bytes. Each codec is enabled by its own feature (`gzip`,
+//! `zstd`, `xz`, `bzip2`), input compressed by disabled codec is
+//! read as is.
+
+use std::io::{BufReader, Cursor, Read, Result};
+
+/// Longest prefix checked by `Compression::detect`
+const MAX_MAGIC_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Detects compression by the first bytes of input
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        // deflate method byte is checked too, as 0x1f is ASV separator
+        if head.starts_with(&[0x1f, 0x8b, 0x08]) {
+            Some(Self::Gzip)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::Zstd)
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::Xz)
+        } else if head.len() >= 10
+            && head.starts_with(b"BZh")
+            && (b'1'..=b'9').contains(&head[3])
+            && head[4..10] == [0x31, 0x41, 0x59, 0x26, 0x53, 0x59]
+        {
+            Some(Self::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    /// Codec feature is enabled
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Self::Gzip => cfg!(feature = "gzip"),
+            Self::Zstd => cfg!(feature = "zstd"),
+            Self::Xz => cfg!(feature = "xz"),
+            Self::Bzip2 => cfg!(feature = "bzip2"),
+        }
+    }
+}
+
+/// Input starts with magic of enabled codec
+pub(crate) fn is_compressed(head: &[u8]) -> bool {
+    Compression::detect(head).is_some_and(Compression::is_enabled)
+}
+
+/// Wraps `reader` into decoder if input is compressed
+pub(crate) fn decoder<'a>(reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+    Ok(detect_decoder(reader)?.0)
+}
+
+/// Same as `decoder`, also returns compression of input,
+/// if it's decompressed
+pub(crate) fn detect_decoder<'a>(mut reader: impl Read + 'a) -> Result<(Box<dyn Read + 'a>, Option<Compression>)> {
+    // pipes may return magic in several short reads
+    let mut head = Vec::with_capacity(MAX_MAGIC_LEN);
+    (&mut reader).take(MAX_MAGIC_LEN as u64).read_to_end(&mut head)?;
+    let compression = Compression::detect(&head).filter(|compression| compression.is_enabled());
+    let reader = BufReader::new(Cursor::new(head).chain(reader));
+
+    let decoder: Box<dyn Read + 'a> = match compression {
+        #[cfg(feature = "gzip")]
+        Some(Compression::Gzip) => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::with_buffer(reader)?),
+        #[cfg(feature = "xz")]
+        Some(Compression::Xz) => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+        #[cfg(feature = "bzip2")]
+        Some(Compression::Bzip2) => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+        _ => Box::new(reader),
+    };
+
+    Ok((decoder, compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Result};
+    use super::decoder;
+
+    /// Returns one byte per read, like a slow pipe
+    struct ByteByByte<'a>(&'a [u8]);
+
+    impl Read for ByteByByte<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = self.0.len().min(buf.len()).min(1);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    fn decode(src: &[u8]) -> Vec<u8> {
+        let mut decoded = vec![];
+        decoder(ByteByByte(src)).unwrap().read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn plain_input_is_unchanged() {
+        assert_eq!(decode(b"a,b\n1,2\n"), b"a,b\n1,2\n");
+        assert_eq!(decode(b"BZh"), b"BZh");
+        assert_eq!(decode(b""), b"");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn magic_in_short_reads() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"a,b\n1,2\n").unwrap();
+        assert_eq!(decode(&encoder.finish().unwrap()), b"a,b\n1,2\n");
+    }
+}
@@ -0,0 +1,70 @@
+use std::cmp::min;
+use memchr::{memchr, memchr2, memchr3};
+
+/// Set of structural bytes to jump between, searched with `memchr`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub(crate) struct ByteSet {
+    needles: Vec<u8>,
+}
+
+impl ByteSet {
+    pub fn new(needles: &[u8]) -> Self {
+        let mut unique: Vec<u8> = needles.to_vec();
+        unique.sort_unstable();
+        unique.dedup();
+
+        Self {
+            needles: unique,
+        }
+    }
+
+    /// Position of the first byte from the set
+    #[inline]
+    pub fn find(&self, haystack: &[u8]) -> Option<usize> {
+        if self.needles.len() <= 3 {
+            return find_group(&self.needles, haystack)
+        }
+
+        // memchr handles up to 3 needles, so larger set is searched by groups,
+        // each next group only before already found byte. Searching in growing
+        // windows prevents rare group from scanning whole haystack every time.
+        const MIN_WINDOW: usize = 64;
+        const MAX_WINDOW: usize = 64 * 1024;
+
+        let mut start = 0;
+        let mut window = MIN_WINDOW;
+        while start < haystack.len() {
+            let mut end = min(start + window, haystack.len());
+            let mut found = None;
+            for group in self.needles.chunks(3) {
+                if let Some(pos) = find_group(group, &haystack[start..end]) {
+                    end = start + pos;
+                    found = Some(end);
+                }
+            }
+
+            if found.is_some() {
+                return found
+            }
+            start = end;
+            window = min(window * 2, MAX_WINDOW);
+        }
+
+        None
+    }
+
+    #[inline]
+    pub fn contains(&self, c: u8) -> bool {
+        self.needles.contains(&c)
+    }
+}
+
+#[inline]
+fn find_group(group: &[u8], haystack: &[u8]) -> Option<usize> {
+    match *group {
+        [a] => memchr(a, haystack),
+        [a, b] => memchr2(a, b, haystack),
+        [a, b, c] => memchr3(a, b, c, haystack),
+        _ => None,
+    }
+}
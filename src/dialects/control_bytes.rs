@@ -0,0 +1,250 @@
+//! ASV has no escaping or quoting, so `0x1f`/`0x1e` bytes inside
+//! cell values would silently shift columns in the output.
+//! This guard applies `ControlBytePolicy` to every written value
+//! (and `Utf8Policy`, see `Utf8Check`), so normalizers write values,
+//! separators and row ends through it.
+
+use std::io::{Error, ErrorKind, Result, Write};
+use memchr::{memchr2, memchr3};
+use crate::asv;
+use super::{ControlBytePolicy, Utf8Policy};
+use super::utf8::Utf8Check;
+
+/// Position dependent part of guard state
+#[cfg(feature = "parallel")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub(crate) struct GuardPosition {
+    pub row: usize,
+    pub cell_affected: bool,
+}
+
+pub(crate) struct ControlByteGuard {
+    policy: ControlBytePolicy,
+    cell_affected: bool,
+    affected_cells: usize,
+    current_row: usize,
+    /// Values are written into it, unless UTF-8 is not checked
+    utf8: Option<Utf8Check>,
+}
+
+impl ControlByteGuard {
+    pub fn new(policy: ControlBytePolicy) -> Self {
+        Self {
+            policy,
+            cell_affected: false,
+            affected_cells: 0,
+            current_row: 0,
+            utf8: None,
+        }
+    }
+
+    /// Applies `policy` to cells too, the first row is the header if `has_header`
+    pub fn with_utf8(mut self, policy: Utf8Policy, has_header: bool) -> Self {
+        self.utf8 = (policy != Utf8Policy::PassThrough).then(|| Utf8Check::new(policy, has_header));
+        self
+    }
+
+    /// Writes (part of) cell value
+    #[inline]
+    pub fn write(&mut self, writer: &mut impl Write, value: &[u8]) -> Result<()> {
+        match &mut self.utf8 {
+            // cell is checked when it ends
+            Some(utf8) => self.policy.write(&mut utf8.cell, value, &mut self.cell_affected, self.current_row),
+            None => self.policy.write(writer, value, &mut self.cell_affected, self.current_row),
+        }
+    }
+
+    #[inline]
+    pub fn end_cell(&mut self) {
+        if self.cell_affected {
+            self.affected_cells += 1;
+            self.cell_affected = false;
+        }
+        if let Some(utf8) = &mut self.utf8 {
+            utf8.end_cell();
+        }
+    }
+
+    /// Writes field separator after the ended cell
+    #[inline]
+    pub fn separator(&mut self, writer: &mut impl Write) -> Result<()> {
+        match &mut self.utf8 {
+            Some(utf8) => {
+                utf8.separator();
+                Ok(())
+            }
+            None => writer.write_all(&[asv::FIELD_SEPARATOR]),
+        }
+    }
+
+    /// Writes record separator
+    #[inline]
+    pub fn end_row(&mut self, writer: &mut impl Write) -> Result<()> {
+        self.end_cell();
+        self.current_row += 1;
+        match &mut self.utf8 {
+            Some(utf8) => utf8.end_row(writer),
+            None => writer.write_all(&[asv::RECORD_SEPARATOR]),
+        }
+    }
+
+    pub fn affected_cells(&self) -> usize {
+        self.affected_cells
+    }
+
+    /// Cells with invalid UTF-8 per output column, see `Utf8Check`
+    pub fn invalid_utf8_cells(&self) -> Vec<usize> {
+        self.utf8
+            .as_ref()
+            .map_or(vec![], |utf8| utf8.affected_cells().to_vec())
+    }
+
+    /// Number of ended rows
+    pub fn row(&self) -> usize {
+        self.current_row
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn position(&self) -> GuardPosition {
+        GuardPosition {
+            row: self.current_row,
+            cell_affected: self.cell_affected,
+        }
+    }
+
+    /// Continue from the middle of the file
+    #[cfg(feature = "parallel")]
+    pub fn set_position(&mut self, position: GuardPosition) {
+        self.current_row = position.row;
+        self.cell_affected = position.cell_affected;
+    }
+}
+
+impl ControlBytePolicy {
+    /// Writes `value`, special bytes of cell in `row` set `cell_affected`
+    #[inline]
+    fn write(self, writer: &mut impl Write, value: &[u8], cell_affected: &mut bool, row: usize) -> Result<()> {
+        if self == ControlBytePolicy::PassThrough {
+            return writer.write_all(value)
+        }
+
+        let mut start = 0;
+        while let Some(found) = self.find_special(&value[start..]) {
+            let pos = start + found;
+            let c = &value[pos];
+
+            writer.write_all(&value[start..pos])?;
+            start = pos + 1;
+
+            if *c != asv::ESCAPE {
+                *cell_affected = true;
+            }
+
+            match self {
+                ControlBytePolicy::Fail => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("ASV separator byte 0x{c:x} in cell value at row {row}")
+                    ))
+                }
+                ControlBytePolicy::PassThrough => unreachable!("values are not checked"),
+                ControlBytePolicy::Strip => {}
+                ControlBytePolicy::Replace(r) if r == asv::FIELD_SEPARATOR || r == asv::RECORD_SEPARATOR => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Replacement byte 0x{r:x} is ASV separator itself")
+                    ))
+                }
+                ControlBytePolicy::Replace(r) => writer.write_all(&[r])?,
+                ControlBytePolicy::Escape => writer.write_all(&[asv::ESCAPE, *c])?,
+            }
+        }
+
+        writer.write_all(&value[start..])
+    }
+
+    #[inline]
+    fn find_special(self, value: &[u8]) -> Option<usize> {
+        if self == ControlBytePolicy::Escape {
+            memchr3(asv::FIELD_SEPARATOR, asv::RECORD_SEPARATOR, asv::ESCAPE, value)
+        } else {
+            memchr2(asv::FIELD_SEPARATOR, asv::RECORD_SEPARATOR, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asv::{self, AsvReader};
+    use crate::dialects::{ControlBytePolicy, Normalize, NormalizeOptions, RecordTerminator, SingleByteDialect};
+    use super::ControlByteGuard;
+
+    /// Cells `a`, `b<0x1f>c` and `d<0x1e>` in one row
+    fn convert(policy: ControlBytePolicy) -> std::io::Result<(Vec<u8>, usize)> {
+        let mut guard = ControlByteGuard::new(policy);
+        let mut asv = vec![];
+        for (i, value) in [b"a".as_slice(), b"b\x1fc", b"d\x1e"].into_iter().enumerate() {
+            if i > 0 {
+                guard.separator(&mut asv)?;
+            }
+            guard.write(&mut asv, value)?;
+            guard.end_cell();
+        }
+        guard.end_row(&mut asv)?;
+        Ok((asv, guard.affected_cells()))
+    }
+
+    #[test]
+    fn fail() {
+        let err = convert(ControlBytePolicy::Fail).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "ASV separator byte 0x1f in cell value at row 0");
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(convert(ControlBytePolicy::Strip).unwrap(), (b"a\x1fbc\x1fd\x1e".to_vec(), 2));
+    }
+
+    #[test]
+    fn replace() {
+        assert_eq!(convert(ControlBytePolicy::Replace(b'_')).unwrap(), (b"a\x1fb_c\x1fd_\x1e".to_vec(), 2));
+        for r in [asv::FIELD_SEPARATOR, asv::RECORD_SEPARATOR] {
+            let err = convert(ControlBytePolicy::Replace(r)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    /// Escape byte itself is escaped too, but cell is not counted
+    #[test]
+    fn escape() {
+        assert_eq!(
+            convert(ControlBytePolicy::Escape).unwrap(),
+            (b"a\x1fb\x1b\x1fc\x1fd\x1b\x1e\x1e".to_vec(), 2),
+        );
+
+        let dialect = SingleByteDialect {
+            header: Some(vec!["a".into(), "b".into()]),
+            field_separator: b',',
+            quote_char: Some(b'"'),
+            empty_columns: vec![false, false],
+            record_terminator: RecordTerminator::Byte(b'\n'),
+            ..Default::default()
+        };
+        let options = NormalizeOptions { control_bytes: ControlBytePolicy::Escape, ..Default::default() };
+        let mut asv = vec![];
+        let src = b"a,b\nx\x1fy,\x1b\n\x1e,\"\x1b\x1e\x1f\"\n";
+        let report = dialect.to_asv(&src[..], &mut asv, &options).unwrap();
+        assert_eq!(report.control_byte_cells, 3);
+
+        let mut reader = AsvReader::new_escaped(&asv[..]);
+        assert_eq!(reader.headers().unwrap(), Some(vec!["a".into(), "b".into()]));
+        let records: Vec<Vec<Vec<u8>>> = reader.records()
+            .map(|record| record.unwrap().iter().map(<[u8]>::to_vec).collect())
+            .collect();
+        assert_eq!(records, [
+            vec![b"x\x1fy".to_vec(), b"\x1b".to_vec()],
+            vec![b"\x1e".to_vec(), b"\x1b\x1e\x1f".to_vec()],
+        ]);
+    }
+}
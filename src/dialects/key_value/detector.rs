@@ -3,12 +3,7 @@
 //! unambiguously (no unescaped/unquoted field/record
 //! separators inside cell values)
 //!
-//! todo! create dialect group for broken csv files, restoring
-//!  rows by detecting ambiguous interval and trying to divide it
-//!  in various ways, determining best by looking up for minimal
-//!  statistic change (min/max col, num/alphanum, enum,
-//!  grex(https://pemistahl.github.io/grex-js/ rules)
-//!
+//! Broken CSV files are restored by `RestoredDialectValidator`
 
 use std::cmp::{min, max};
 use crate::dialects::key_value::KeyValueDialect;
@@ -40,15 +35,22 @@ impl DialectGroupValidator for KeyValueDialectValidator {
     }
 
 
-    fn finalize(&mut self) -> Option<Dialect> {
+    fn finalize(&mut self) -> Vec<Dialect> {
+        // last row may have no terminator
+        if (self.current_col > 0 || self.current_cell_byte > 0) && self.end_row().is_err() {
+            return vec![]
+        }
+
         // if >50% rows are just key:value
         if self.broken_rows * 2 < self.current_row  {
-            Some(Dialect::KeyValue(KeyValueDialect {
+            vec![Dialect::KeyValue(KeyValueDialect {
                 total_rows: self.current_row,
                 field_separator: self.field_separator,
-            }))
+                encoding: None,
+                bom: None,
+            })]
         } else {
-            None
+            vec![]
         }
     }
 }
@@ -57,9 +59,10 @@ impl DialectGroupValidator for KeyValueDialectValidator {
 impl KeyValueDialectValidator {
     #[allow(clippy::single_element_loop)]
     pub fn make() -> Vec<Self> {
-        let mut v = Self::default();
-        v.field_separator = b':';
-        vec![v]
+        vec![Self {
+            field_separator: b':',
+            ..Default::default()
+        }]
     }
 
     #[inline]
@@ -123,6 +126,7 @@ impl KeyValueDialectValidator {
         if self.current_col == 0 {
             return Err("Only one column found")
         }
+        self.current_cell_byte = 0;
 
         if self.current_col != 1 {
             self.broken_rows += 1;
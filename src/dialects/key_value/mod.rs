@@ -2,9 +2,14 @@ mod detector;
 mod normalizer;
 
 pub use detector::*;
+use crate::bom::Bom;
 
 #[derive(Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct KeyValueDialect {
     pub total_rows: usize,
     pub field_separator: u8,
+    /// See `SingleByteDialect::encoding`
+    pub encoding: Option<&'static str>,
+    /// See `SingleByteDialect::bom`
+    pub bom: Option<Bom>,
 }
@@ -1,16 +1,32 @@
-use std::io::{Read, Write};
-use crate::dialects::{Normalize};
+use std::io::{self, Read, Result, Write};
+use memchr::memchr3;
+use crate::dialects::{Normalize, NormalizeOptions, NormalizeReport};
+use crate::dialects::control_bytes::ControlByteGuard;
 use crate::dialects::key_value::KeyValueDialect;
 
 impl Normalize for KeyValueDialect {
-    fn to_asv(&self, src: impl Read, dst: impl Write) {
+    fn to_asv(&self, src: impl Read, dst: impl Write, options: &NormalizeOptions) -> Result<NormalizeReport> {
         let mut normalizer = KeyValueDialectNormalizer::new(
             src,
             dst,
-            self.clone()
+            self.clone(),
+            options,
         );
 
-        normalizer.normalize();
+        normalizer.normalize()?;
+        Ok(normalizer.report())
+    }
+
+    fn slice_to_asv(&self, src: &[u8], dst: impl Write, options: &NormalizeOptions) -> Result<NormalizeReport> {
+        let mut normalizer = KeyValueDialectNormalizer::new(
+            io::empty(),
+            dst,
+            self.clone(),
+            options,
+        );
+
+        normalizer.normalize_slice(src)?;
+        Ok(normalizer.report())
     }
 }
 struct KeyValueDialectNormalizer <W: Write, R: Read> {
@@ -18,88 +34,132 @@ struct KeyValueDialectNormalizer <W: Write, R: Read> {
     reader: R,
     dialect: KeyValueDialect,
     current_column: usize,
+    is_row_started: bool,
+    guard: ControlByteGuard,
 }
 
 impl<W: Write, R: Read> KeyValueDialectNormalizer<W, R> {
-    fn new(reader: R, writer: W, dialect: KeyValueDialect) -> Self {
+    fn new(reader: R, writer: W, dialect: KeyValueDialect, options: &NormalizeOptions) -> Self {
         Self {
             writer,
             reader,
             dialect,
             current_column: 0,
+            is_row_started: false,
+            // header is written as is
+            guard: ControlByteGuard::new(options.control_bytes).with_utf8(options.invalid_utf8, false),
         }
     }
 
-    fn normalize(&mut self) {
-        self.writer.write_all(b"login\x1fpassword\x1e").unwrap();
+    fn report(&self) -> NormalizeReport {
+        NormalizeReport {
+            control_byte_cells: self.guard.affected_cells(),
+            record_index: None,
+            invalid_utf8_cells: self.guard.invalid_utf8_cells(),
+            comments: vec![],
+            footer: vec![],
+            blank_lines: 0,
+            repaired_rows: vec![],
+        }
+    }
+
+    fn normalize(&mut self) -> Result<()> {
+        self.writer.write_all(b"login\x1fpassword\x1e")?;
 
         let mut buffer = vec![b'0'; 1024*1024*16]; // 16 MiB chunks
         loop {
-            let chunk_size = self.reader.read(&mut buffer).unwrap();
+            let chunk_size = self.reader.read(&mut buffer)?;
             if chunk_size == 0 {
                 break
             }
 
-            self.process_chunk(&buffer[0..chunk_size]);
+            self.process_chunk(&buffer[0..chunk_size])?;
         }
+
+        self.finish()?;
+        self.writer.flush()
     }
 
-    fn process_chunk(&mut self, chunk: &[u8]) {
-        for c in chunk {
-            // these try_* functions returns true if byte is accepted/consumed
-            if self.try_next_row(c) {
-                continue;
+    fn normalize_slice(&mut self, src: &[u8]) -> Result<()> {
+        self.writer.write_all(b"login\x1fpassword\x1e")?;
+        self.process_chunk(src)?;
+        self.finish()?;
+        self.writer.flush()
+    }
+
+    /// Last row may have no terminator
+    fn finish(&mut self) -> Result<()> {
+        if self.is_row_started {
+            self.end_row()?;
+        }
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        while pos < chunk.len() {
+            // copy whole run of plain bytes at once
+            let rest = &chunk[pos..];
+            let span = memchr3(b'\r', b'\n', self.dialect.field_separator, rest)
+                .unwrap_or(rest.len());
+            if span > 0 {
+                self.is_row_started = true;
+                self.guard.write(&mut self.writer, &rest[..span])?;
+                pos += span;
+                continue
             }
 
-            if self.try_next_field(c) {
+            // these try_* functions returns true if byte is accepted/consumed
+            let c = &rest[0];
+            pos += 1;
+            if self.try_next_row(c)? {
                 continue;
             }
 
-            self.try_next_char(c);
+            self.try_next_field(c)?;
         }
+
+        Ok(())
     }
 
     #[inline]
-    fn try_next_row(&mut self, c: &u8) -> bool {
+    fn try_next_row(&mut self, c: &u8) -> Result<bool> {
         if *c == b'\r' {
-            return true; // consume CR byte, but no line break here yet
+            Ok(true) // consume CR byte, but no line break here yet
         } else if *c == b'\n'{
-            self.end_row();
-            true
+            self.end_row()?;
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
     #[inline]
-    fn try_next_field(&mut self, c: &u8) -> bool {
+    fn try_next_field(&mut self, c: &u8) -> Result<bool> {
         if *c == self.dialect.field_separator {
-            self.end_field();
-            true
+            self.is_row_started = true;
+            self.end_field()?;
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
     #[inline]
-    fn try_next_char(&mut self, c: &u8) -> bool {
-        self.writer.write_all(&[*c]).unwrap();
-        true
-    }
-
-    #[inline]
-    fn end_field(&mut self) {
+    fn end_field(&mut self) -> Result<()> {
         if self.current_column == 0 {
-            self.writer.write_all(b"\x1f").unwrap();
+            self.guard.end_cell();
+            self.guard.separator(&mut self.writer)?;
             self.current_column = 1;
         }
+        Ok(())
     }
 
 
     #[inline]
-    fn end_row(&mut self) {
+    fn end_row(&mut self) -> Result<()> {
         self.current_column = 0;
-        self.writer.write_all(b"\x1e").unwrap();
+        self.is_row_started = false;
+        self.guard.end_row(&mut self.writer)
     }
 }
-
@@ -0,0 +1,97 @@
+//! Search of line prefixes (e.g. comment prefixes) in chunks of input,
+//! shared by all validators. Validators of the same record terminator
+//! would otherwise scan every chunk for the same bytes.
+
+use std::cell::RefCell;
+use memchr::memmem;
+
+/// Longest prefix which is found across chunks
+pub(crate) const MAX_LINE_PREFIX_LEN: usize = 2;
+
+/// Chunk passed to `DialectGroupValidator::spawn`
+pub struct LineStarts<'a> {
+    chunk: &'a [u8],
+    /// Last `MAX_LINE_PREFIX_LEN` bytes of previous chunks, `None`
+    /// for the first chunk, as file start is a line start too
+    tail: Option<&'a [u8]>,
+    /// Results of `has_line_starting_with` by line break and prefix
+    found: RefCell<Vec<(u8, &'static [u8], bool)>>,
+}
+
+impl<'a> LineStarts<'a> {
+    pub(crate) fn new(chunk: &'a [u8], tail: Option<&'a [u8]>) -> Self {
+        Self {
+            chunk,
+            tail,
+            found: RefCell::new(vec![]),
+        }
+    }
+
+    /// Some line (ended by `line_break_end`) starts with `prefix` in
+    /// this chunk. Quoting is not taken into account, so it may be a
+    /// false alarm.
+    pub fn has_line_starting_with(&self, line_break_end: u8, prefix: &'static [u8]) -> bool {
+        debug_assert!(prefix.len() <= MAX_LINE_PREFIX_LEN);
+        let found = self.found
+            .borrow()
+            .iter()
+            .find(|(end, p, _)| *end == line_break_end && *p == prefix)
+            .map(|(_, _, found)| *found);
+
+        found.unwrap_or_else(|| {
+            let found = self.search(line_break_end, prefix);
+            self.found.borrow_mut().push((line_break_end, prefix, found));
+            found
+        })
+    }
+
+    fn search(&self, line_break_end: u8, prefix: &[u8]) -> bool {
+        let mut needle = vec![line_break_end];
+        needle.extend_from_slice(prefix);
+        if memmem::find(self.chunk, &needle).is_some() {
+            return true
+        }
+
+        // line break may be in previous chunks
+        let tail = self.tail.unwrap_or(&needle[..1]);
+        let head = &self.chunk[..self.chunk.len().min(prefix.len())];
+        if memmem::find(&[tail, head].concat(), &needle).is_some() {
+            return true
+        }
+
+        // prefix may end in the next chunk, but then its first bytes
+        // are already a part of row, so validator is spawned right now
+        let end = [tail, &self.chunk[self.chunk.len().saturating_sub(prefix.len())..]].concat();
+        (1..prefix.len()).any(|len| end.ends_with(&needle[..=len]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineStarts;
+
+    #[test]
+    fn file_start_is_line_start() {
+        assert!(LineStarts::new(b"# comment\na,b\n", None).has_line_starting_with(b'\n', b"#"));
+        assert!(!LineStarts::new(b"# comment\na,b\n", Some(b"x")).has_line_starting_with(b'\n', b"#"));
+    }
+
+    #[test]
+    fn prefix_across_chunks() {
+        // line break in previous chunk
+        assert!(LineStarts::new(b"/ comment\n", Some(b"\n/")).has_line_starting_with(b'\n', b"//"));
+        // prefix ends in the next chunk
+        assert!(LineStarts::new(b"a,b\n/", Some(b"\n")).has_line_starting_with(b'\n', b"//"));
+        assert!(!LineStarts::new(b"a,b\n", Some(b"\n")).has_line_starting_with(b'\n', b"//"));
+    }
+
+    #[test]
+    fn search_is_done_once() {
+        let lines = LineStarts::new(b"a;b\r#c\r", Some(b"\r"));
+        for _ in 0..3 {
+            assert!(lines.has_line_starting_with(b'\r', b"#"));
+            assert!(!lines.has_line_starting_with(b'\n', b"#"));
+        }
+        assert_eq!(lines.found.borrow().len(), 2);
+    }
+}
@@ -1,37 +1,385 @@
 mod single_byte;
 mod key_value;
+mod restored;
+pub(crate) mod control_bytes;
+mod byte_set;
+mod utf8;
+mod line_starts;
 
-use std::io::{Read, Write};
-pub use single_byte::{SingleByteDialectValidator, SingleByteDialect};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::asv::AsvRecord;
+use crate::index::RecordIndex;
+use crate::{bom, decompress, mapped};
+use crate::bom::Bom;
+#[cfg(feature = "encoding")]
+use crate::encoding;
+pub use single_byte::{SingleByteDialectValidator, SingleByteDialect, SingleByteDialectReader, SingleByteDialectRecords, SingleByteDialectWriter, RecordTerminator, Quoting, StrayQuotes, EscapeScope, BlankLines, MalformedRow};
 pub use key_value::{KeyValueDialectValidator, KeyValueDialect};
+pub use restored::{RestoredDialectValidator, RestoredDialect};
+pub use line_starts::LineStarts;
+pub(crate) use line_starts::MAX_LINE_PREFIX_LEN;
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum Dialect {
+    /// Last resort, so it's the least preferred
+    Restored(RestoredDialect),
     SingleByte(SingleByteDialect),
     KeyValue(KeyValueDialect)
 }
 
 pub trait DialectGroupValidator {
     fn try_process_chunk(&mut self, chunk: &[u8]) -> Result<(), String>;
-    fn finalize(&mut self) -> Option<Dialect>;
+    fn finalize(&mut self) -> Vec<Dialect>;
+
+    /// Validators of dialect variants which differ only if chunk has
+    /// something special (e.g. comment lines), so they are not created
+    /// until then. Called before `try_process_chunk` with the same chunk,
+    /// so new validators start in the same state.
+    fn spawn(&mut self, _lines: &LineStarts) -> Vec<Box<dyn DialectGroupValidator + Send>> {
+        vec![]
+    }
+
+    /// Set before any chunk is processed
+    fn set_tolerance(&mut self, _tolerance: Tolerance) {}
+}
+
+/// Malformed records (rows of different shape inside table) which
+/// dialect survives, see `SingleByteDialect::malformed_rows`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Tolerance {
+    /// Any malformed record disqualifies dialect
+    #[default] Strict,
+    /// Up to this number of records
+    Records(usize),
+    /// Up to this share of records, in parts per million
+    PartsPerMillion(u32),
+}
+
+/// What to do with `0x1f`/`0x1e` bytes found inside cell values,
+/// ASV output can't represent them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ControlBytePolicy {
+    /// Write them as is, so these values shift columns of
+    /// the output (as conversion always did before)
+    #[default] PassThrough,
+    /// Stop conversion with an error
+    Fail,
+    /// Drop these bytes from value
+    Strip,
+    /// Replace each of these bytes with given byte, conversion
+    /// fails if it's `0x1f` or `0x1e` itself
+    Replace(u8),
+    /// Write escaped ASV variant: `0x1b` is prepended to every
+    /// `0x1b`, `0x1f` and `0x1e` byte in values
+    /// (see `asv::AsvReader::new_escaped`)
+    Escape,
+}
+
+/// What to do with cells which are not valid UTF-8
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Utf8Policy {
+    /// Write them as is, cells are not checked
+    #[default] PassThrough,
+    /// Replace invalid sequences with U+FFFD
+    Replace,
+    /// Write empty cell instead
+    DropCell,
+    /// Skip whole row
+    DropRow,
+    /// Write every invalid byte as `\xNN`, `\` is written as `\x5C`
+    /// in all cells, so values are decoded back unambiguously
+    HexEscape,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NormalizeOptions {
+    pub control_bytes: ControlBytePolicy,
+    /// Build `RecordIndex` of source file, keeping offset of every
+    /// N-th record. Conversion fails if dialect is not single-byte, or
+    /// input is compressed or decoded (offsets wouldn't be in the file).
+    pub record_index: Option<u64>,
+    /// Decode values from detected `encoding` into UTF-8 (`encoding`
+    /// feature). Encodings which can't be tokenized byte by byte
+    /// (UTF-16, Shift_JIS, ...) are always decoded.
+    pub transcode_to_utf8: bool,
+    /// Checked after decoding
+    pub invalid_utf8: Utf8Policy,
+    /// Return comment lines in `NormalizeReport::comments`,
+    /// otherwise they are just dropped
+    pub keep_comments: bool,
+    /// Return footer rows in `NormalizeReport::footer`,
+    /// otherwise they are just dropped
+    pub keep_footer: bool,
+    /// Write malformed records here as they are in the source (with
+    /// terminator), otherwise they are just dropped
+    pub quarantine: Option<QuarantineSink>,
+}
+
+/// Shared writer for malformed records, so that options stay `Clone`
+/// (e.g. one sink for all members of archive)
+#[derive(Clone)]
+pub struct QuarantineSink(pub Arc<Mutex<dyn Write + Send>>);
+
+impl QuarantineSink {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+
+    pub(crate) fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        self.lock()?.write_all(bytes)
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, dyn Write + Send + 'static>> {
+        self.0.lock().map_err(|_| io::Error::other("Quarantine writer is poisoned"))
+    }
+}
+
+impl std::fmt::Debug for QuarantineSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("QuarantineSink")
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NormalizeReport {
+    /// Cells which contained `0x1f`/`0x1e` bytes
+    pub control_byte_cells: usize,
+    /// Built if `NormalizeOptions::record_index` is set
+    pub record_index: Option<RecordIndex>,
+    /// Cells with invalid UTF-8 per output column (header is not
+    /// counted), unless `NormalizeOptions::invalid_utf8` is `PassThrough`
+    pub invalid_utf8_cells: Vec<usize>,
+    /// Comment lines (with prefix, without terminator), if
+    /// `NormalizeOptions::keep_comments` is set
+    pub comments: Vec<Vec<u8>>,
+    /// Footer rows, if `NormalizeOptions::keep_footer` is set
+    pub footer: Vec<AsvRecord>,
+    /// Blank lines dropped by `BlankLines::Skip`
+    pub blank_lines: usize,
+    /// Records restored from broken rows by `RestoredDialect`
+    /// (0 is the first data record)
+    pub repaired_rows: Vec<u64>,
 }
 
 trait Normalize {
     fn to_asv(
         &self,
         src: impl Read,
-        dest: impl Write
-    );
+        dest: impl Write,
+        options: &NormalizeOptions,
+    ) -> io::Result<NormalizeReport>;
+
+    /// Same as `to_asv` for file which is fully in memory, without copying it
+    fn slice_to_asv(
+        &self,
+        src: &[u8],
+        dest: impl Write,
+        options: &NormalizeOptions,
+    ) -> io::Result<NormalizeReport>;
 }
 
 impl Dialect {
-    pub fn to_asv(&self, src: impl Read, dest: impl Write) {
+    /// Converts file to ASV, compressed input is decompressed
+    /// (see `Compression`).
+    pub fn to_asv(&self, src: impl Read, dest: impl Write) -> io::Result<NormalizeReport> {
+        self.to_asv_with_options(src, dest, &NormalizeOptions::default())
+    }
+
+    pub fn to_asv_with_options(
+        &self,
+        src: impl Read,
+        dest: impl Write,
+        options: &NormalizeOptions,
+    ) -> io::Result<NormalizeReport> {
+        let (mut src, compression) = decompress::detect_decoder(src)?;
+        self.check_record_index(options, compression.is_some())?;
+        bom::skip(&mut src, self.bom())?;
+        #[cfg(feature = "encoding")]
+        let src = match self.source_encoding(options) {
+            Some(encoding) => Box::new(encoding::TranscodingReader::new(src, encoding)),
+            None => src,
+        };
+
+        match self {
+            Dialect::SingleByte(sb) => {
+                sb.to_asv(src, dest, options)
+            }
+            Dialect::KeyValue(kv) => {
+                kv.to_asv(src, dest, options)
+            }
+            Dialect::Restored(restored) => {
+                restored.to_asv(src, dest, options)
+            }
+        }
+    }
+
+    /// Same as `to_asv_with_options`, but local files are memory-mapped
+    /// instead of being copied through `Read`. Special files (pipes,
+    /// devices) are read as usual.
+    ///
+    /// File must not be modified while it's converted.
+    pub fn to_asv_path(
+        &self,
+        path: impl AsRef<Path>,
+        dest: impl Write,
+        options: &NormalizeOptions,
+    ) -> io::Result<NormalizeReport> {
+        let file = File::open(path)?;
+        match mapped::map(&file) {
+            Some(map) if decompress::is_compressed(&map) || self.needs_decoding(options) => {
+                self.to_asv_with_options(&map[..], dest, options)
+            }
+            Some(map) => self.slice_to_asv(&map, dest, options),
+            None => self.to_asv_with_options(file, dest, options),
+        }
+    }
+
+    /// Same as `to_asv_with_options` for file which is fully in memory,
+    /// but uses `threads` threads (0 = one per CPU). Output is the same.
+    /// Compressed or decoded input is converted sequentially, as well as
+    /// input checked by `Utf8Policy` (rows may cross ranges, but may be dropped).
+    #[cfg(feature = "parallel")]
+    pub fn to_asv_parallel(
+        &self,
+        src: &[u8],
+        dest: impl Write,
+        options: &NormalizeOptions,
+        threads: usize,
+    ) -> io::Result<NormalizeReport> {
+        if decompress::is_compressed(src) || self.needs_decoding(options) {
+            return self.to_asv_with_options(src, dest, options)
+        }
+        if options.invalid_utf8 != Utf8Policy::PassThrough {
+            return self.slice_to_asv(src, dest, options)
+        }
+
+        self.check_record_index(options, false)?;
+        let src = bom::strip(src, self.bom());
         match self {
             Dialect::SingleByte(sb) => {
-                sb.to_asv(src, dest)
+                sb.to_asv_parallel(src, dest, options, threads)
             }
+            // converted sequentially
             Dialect::KeyValue(kv) => {
-                kv.to_asv(src, dest)
+                kv.slice_to_asv(src, dest, options)
+            }
+            Dialect::Restored(restored) => {
+                restored.slice_to_asv(src, dest, options)
+            }
+        }
+    }
+
+    /// `to_asv_path` on `threads` threads, see `to_asv_parallel`.
+    /// Special files are converted sequentially.
+    #[cfg(feature = "parallel")]
+    pub fn to_asv_path_parallel(
+        &self,
+        path: impl AsRef<Path>,
+        dest: impl Write,
+        options: &NormalizeOptions,
+        threads: usize,
+    ) -> io::Result<NormalizeReport> {
+        let file = File::open(path)?;
+        match mapped::map(&file) {
+            Some(map) => self.to_asv_parallel(&map, dest, options, threads),
+            None => self.to_asv_with_options(file, dest, options),
+        }
+    }
+
+    pub fn bom(&self) -> Option<Bom> {
+        match self {
+            Dialect::SingleByte(sb) => sb.bom,
+            Dialect::KeyValue(kv) => kv.bom,
+            Dialect::Restored(restored) => restored.bom,
+        }
+    }
+
+    pub(crate) fn set_bom(&mut self, bom: Option<Bom>) {
+        match self {
+            Dialect::SingleByte(sb) => sb.bom = bom,
+            Dialect::KeyValue(kv) => kv.bom = bom,
+            Dialect::Restored(restored) => restored.bom = bom,
+        }
+    }
+
+    pub fn encoding(&self) -> Option<&'static str> {
+        match self {
+            Dialect::SingleByte(sb) => sb.encoding,
+            Dialect::KeyValue(kv) => kv.encoding,
+            Dialect::Restored(restored) => restored.encoding,
+        }
+    }
+
+    #[cfg(feature = "encoding")]
+    pub(crate) fn set_encoding(&mut self, encoding: &'static str) {
+        match self {
+            Dialect::SingleByte(sb) => sb.encoding = Some(encoding),
+            Dialect::KeyValue(kv) => kv.encoding = Some(encoding),
+            Dialect::Restored(restored) => restored.encoding = Some(encoding),
+        }
+    }
+
+    /// Encoding to decode input from before conversion
+    #[cfg(feature = "encoding")]
+    fn source_encoding(&self, options: &NormalizeOptions) -> Option<&'static encoding_rs::Encoding> {
+        let encoding = encoding_rs::Encoding::for_label(self.encoding()?.as_bytes())?;
+        let is_utf8 = encoding == encoding_rs::UTF_8;
+        let should_decode = encoding::needs_decoding(encoding) || (options.transcode_to_utf8 && !is_utf8);
+
+        should_decode.then_some(encoding)
+    }
+
+    /// Record index is built only by single-byte dialects, and its
+    /// offsets must be offsets in the source file itself
+    fn check_record_index(&self, options: &NormalizeOptions, is_compressed: bool) -> io::Result<()> {
+        if options.record_index.is_none() {
+            return Ok(())
+        }
+        if !matches!(self, Dialect::SingleByte(_)) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Record index is built only for single-byte dialects"))
+        }
+        if is_compressed || self.needs_decoding(options) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Record index of compressed or decoded input is not supported"))
+        }
+        Ok(())
+    }
+
+    fn needs_decoding(&self, options: &NormalizeOptions) -> bool {
+        #[cfg(feature = "encoding")]
+        {
+            self.source_encoding(options).is_some()
+        }
+        #[cfg(not(feature = "encoding"))]
+        {
+            let _ = options;
+            false
+        }
+    }
+
+    fn slice_to_asv(
+        &self,
+        src: &[u8],
+        dest: impl Write,
+        options: &NormalizeOptions,
+    ) -> io::Result<NormalizeReport> {
+        self.check_record_index(options, false)?;
+        let src = bom::strip(src, self.bom());
+        match self {
+            Dialect::SingleByte(sb) => {
+                sb.slice_to_asv(src, dest, options)
+            }
+            Dialect::KeyValue(kv) => {
+                kv.slice_to_asv(src, dest, options)
+            }
+            Dialect::Restored(restored) => {
+                restored.slice_to_asv(src, dest, options)
             }
         }
     }
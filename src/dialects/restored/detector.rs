@@ -0,0 +1,151 @@
+//! This dialect group restores broken CSV files, which can't be
+//! parsed unambiguously by single-byte dialects: unquoted values
+//! contain field separators or line breaks. Rows of such values
+//! are detected as ambiguous intervals and divided by minimal
+//! change of column statistics (class of values, length), see
+//! `restorer`.
+
+use std::ops::Range;
+use crate::dialects::restored::RestoredDialect;
+use super::restorer::{Restorer, RowSink};
+use super::super::{Dialect, DialectGroupValidator};
+
+/// Rows checked before share of repaired ones is limited
+const MIN_CHECKED_ROWS: usize = 1000;
+/// Repaired rows share limit, more likely means wrong separator
+const MAX_REPAIRED_PERCENT: usize = 10;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct RestoredDialectValidator {
+    restorer: Restorer,
+    rows: RowCounter,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+struct RowCounter {
+    field_separator: u8,
+    columns: usize,
+    rows: usize,
+    repaired_rows: usize,
+}
+
+impl RowCounter {
+    fn has_too_many_repaired(&self) -> bool {
+        self.repaired_rows * 100 > self.rows * MAX_REPAIRED_PERCENT
+    }
+}
+
+impl RowSink for RowCounter {
+    type Error = &'static str;
+
+    fn row(&mut self, _bytes: &[u8], cells: &[Range<usize>], repaired: bool) -> Result<(), Self::Error> {
+        if self.rows == 0 {
+            self.columns = cells.len();
+        }
+        self.rows += 1;
+        self.repaired_rows += repaired as usize;
+
+        if self.rows >= MIN_CHECKED_ROWS && self.has_too_many_repaired() {
+            return Err("Too many rows need restoration")
+        }
+        Ok(())
+    }
+
+    fn malformed(&mut self, reason: &'static str) -> Result<(), Self::Error> {
+        Err(reason)
+    }
+}
+
+impl DialectGroupValidator for RestoredDialectValidator {
+    fn try_process_chunk(&mut self, chunk: &[u8]) -> Result<(), String> {
+        self.restorer
+            .process_chunk(chunk, &mut self.rows)
+            .map_err(|e| format!("{e} at row {}", self.rows.rows))
+    }
+
+    fn finalize(&mut self) -> Vec<Dialect> {
+        if self.restorer.finish(&mut self.rows).is_err() {
+            return vec![]
+        }
+
+        // files without broken rows are single-byte dialects
+        let rows = &self.rows;
+        if rows.repaired_rows == 0 || rows.has_too_many_repaired() {
+            return vec![]
+        }
+
+        vec![Dialect::Restored(RestoredDialect {
+            total_rows: rows.rows,
+            repaired_rows: rows.repaired_rows,
+            columns: rows.columns,
+            field_separator: rows.field_separator,
+            encoding: None,
+            bom: None,
+        })]
+    }
+}
+
+impl RestoredDialectValidator {
+    /// First line is always the header, so nothing
+    /// is restored if file is known to have none
+    pub fn make(has_headers: Option<bool>) -> Vec<Self> {
+        if has_headers == Some(false) {
+            return vec![]
+        }
+
+        [b',', b';', b'\t', b'|']
+            .into_iter()
+            .map(|field_separator| Self {
+                restorer: Restorer::new(field_separator),
+                rows: RowCounter {
+                    field_separator,
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CsvSniffer, Dialect};
+
+    /// Table with one row, which has field separator in value
+    fn source() -> String {
+        let mut src = "id,comment,amount\n".to_string();
+        for i in 1..=30 {
+            let comment = if i == 17 { "great view, friendly staff" } else { "nice and quiet place" };
+            src.push_str(&format!("{i},{comment},{}\n", i * 10));
+        }
+        src
+    }
+
+    #[test]
+    fn stream_and_mapped_file() {
+        let src = source();
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process(&mut src.as_bytes());
+        let dialects = sniffer.dialects();
+        let [Dialect::Restored(dialect)] = &dialects[..] else {
+            panic!("Only restored dialect is expected, found {dialects:?}")
+        };
+        assert_eq!((dialect.total_rows, dialect.repaired_rows, dialect.columns), (31, 1, 3));
+
+        let path = std::env::temp_dir().join(format!("brutal-csv-restored-{}.csv", std::process::id()));
+        std::fs::write(&path, &src).unwrap();
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sniffer.dialects(), dialects);
+    }
+
+    #[test]
+    fn not_restored_if_other_dialect_fits() {
+        let src = source().replace("great view, friendly staff", "\"great view, friendly staff\"");
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process(&mut src.as_bytes());
+        let dialects = sniffer.dialects();
+        assert!(!dialects.is_empty());
+        assert!(dialects.iter().all(|dialect| matches!(dialect, Dialect::SingleByte(_))));
+    }
+}
@@ -0,0 +1,42 @@
+mod detector;
+mod normalizer;
+mod restorer;
+
+use std::cmp::Ordering;
+pub use detector::RestoredDialectValidator;
+use crate::bom::Bom;
+
+/// Broken CSV: no quoting, but some values contain field separators or
+/// line breaks, such rows are restored by column statistics (see
+/// `NormalizeReport::repaired_rows`). First line is the header.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct RestoredDialect {
+    /// Rows after restoration, including header
+    pub total_rows: usize,
+    /// Rows restored from ambiguous intervals
+    pub repaired_rows: usize,
+    pub columns: usize,
+    pub field_separator: u8,
+    /// See `SingleByteDialect::encoding`
+    pub encoding: Option<&'static str>,
+    /// See `SingleByteDialect::bom`
+    pub bom: Option<Bom>,
+}
+
+impl PartialOrd<Self> for RestoredDialect {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RestoredDialect {
+    /// Fewer repaired rows is preferred, then more columns
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.repaired_rows.cmp(&self.repaired_rows)
+            .then(self.columns.cmp(&other.columns))
+            .then(self.total_rows.cmp(&other.total_rows))
+            .then(self.field_separator.cmp(&other.field_separator))
+            .then(self.encoding.cmp(&other.encoding))
+            .then(self.bom.cmp(&other.bom))
+    }
+}
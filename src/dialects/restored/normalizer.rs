@@ -0,0 +1,117 @@
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+use std::ops::Range;
+use crate::dialects::{Normalize, NormalizeOptions, NormalizeReport};
+use crate::dialects::control_bytes::ControlByteGuard;
+use crate::dialects::restored::RestoredDialect;
+use super::restorer::{Restorer, RowSink};
+
+impl Normalize for RestoredDialect {
+    fn to_asv(&self, src: impl Read, dst: impl Write, options: &NormalizeOptions) -> Result<NormalizeReport> {
+        let mut normalizer = RestoredDialectNormalizer::new(src, dst, self, options);
+        normalizer.normalize()?;
+        Ok(normalizer.into_report())
+    }
+
+    fn slice_to_asv(&self, src: &[u8], dst: impl Write, options: &NormalizeOptions) -> Result<NormalizeReport> {
+        let mut normalizer = RestoredDialectNormalizer::new(io::empty(), dst, self, options);
+        normalizer.normalize_slice(src)?;
+        Ok(normalizer.into_report())
+    }
+}
+
+struct RestoredDialectNormalizer<W: Write, R: Read> {
+    reader: R,
+    restorer: Restorer,
+    output: AsvRows<W>,
+}
+
+/// Writes restored rows, header is the first one
+struct AsvRows<W: Write> {
+    writer: W,
+    guard: ControlByteGuard,
+    /// Data records written
+    records: u64,
+    is_header: bool,
+    repaired_rows: Vec<u64>,
+}
+
+impl<W: Write, R: Read> RestoredDialectNormalizer<W, R> {
+    fn new(reader: R, writer: W, dialect: &RestoredDialect, options: &NormalizeOptions) -> Self {
+        Self {
+            reader,
+            restorer: Restorer::new(dialect.field_separator),
+            output: AsvRows {
+                writer,
+                guard: ControlByteGuard::new(options.control_bytes).with_utf8(options.invalid_utf8, true),
+                records: 0,
+                is_header: true,
+                repaired_rows: vec![],
+            },
+        }
+    }
+
+    fn into_report(self) -> NormalizeReport {
+        NormalizeReport {
+            control_byte_cells: self.output.guard.affected_cells(),
+            record_index: None,
+            invalid_utf8_cells: self.output.guard.invalid_utf8_cells(),
+            comments: vec![],
+            footer: vec![],
+            blank_lines: 0,
+            repaired_rows: self.output.repaired_rows,
+        }
+    }
+
+    fn normalize(&mut self) -> Result<()> {
+        let mut buffer = vec![b'0'; 1024*1024*16]; // 16 MiB chunks
+        loop {
+            let chunk_size = self.reader.read(&mut buffer)?;
+            if chunk_size == 0 {
+                break
+            }
+
+            self.restorer.process_chunk(&buffer[0..chunk_size], &mut self.output)?;
+        }
+
+        self.finish()
+    }
+
+    fn normalize_slice(&mut self, src: &[u8]) -> Result<()> {
+        self.restorer.process_chunk(src, &mut self.output)?;
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.restorer.finish(&mut self.output)?;
+        self.output.writer.flush()
+    }
+}
+
+impl<W: Write> RowSink for AsvRows<W> {
+    type Error = Error;
+
+    fn row(&mut self, bytes: &[u8], cells: &[Range<usize>], repaired: bool) -> Result<()> {
+        for (i, cell) in cells.iter().enumerate() {
+            if i != 0 {
+                self.guard.separator(&mut self.writer)?;
+            }
+            self.guard.write(&mut self.writer, &bytes[cell.clone()])?;
+            self.guard.end_cell();
+        }
+        self.guard.end_row(&mut self.writer)?;
+
+        if self.is_header {
+            self.is_header = false;
+            return Ok(())
+        }
+        if repaired {
+            self.repaired_rows.push(self.records);
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn malformed(&mut self, reason: &'static str) -> Result<()> {
+        Err(Error::new(ErrorKind::InvalidData, reason))
+    }
+}
@@ -0,0 +1,414 @@
+//! Splits broken CSV stream into lines and restores its rows.
+//!
+//! Header defines number of columns. Lines which have a different
+//! number of fields (because of unquoted field separators or line
+//! breaks inside values) form an ambiguous interval, which ends with
+//! the next line of table shape. The interval is divided into rows in
+//! every possible way (cell may take several fields and lines) and the
+//! division which changes column statistics least is taken.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+use memchr::{memchr, memchr_iter};
+
+/// Lines in one ambiguous interval
+const MAX_INTERVAL_LINES: usize = 10;
+/// Fields in one ambiguous interval
+const MAX_INTERVAL_FIELDS: usize = 256;
+/// Fields (and lines) one restored cell may take
+const MAX_CELL_FIELDS: usize = 16;
+const MAX_LINE_BYTES: usize = 64 * 1024;
+/// Cost of every field separator or line break kept inside restored
+/// cell, in thousandths (see `ColumnStats::cost`): the same as value
+/// unusual by class, so divisions with fewer merges (more rows) win
+/// and statistics choose between divisions with the same number
+const MERGE_COST: usize = 1000;
+
+pub(crate) trait RowSink {
+    type Error;
+
+    /// Header or data row, `cells` are ranges of `bytes`. Cells of
+    /// `repaired` row may contain field separators and line breaks.
+    fn row(&mut self, bytes: &[u8], cells: &[Range<usize>], repaired: bool) -> Result<(), Self::Error>;
+    /// Input can't be restored with this dialect
+    fn malformed(&mut self, reason: &'static str) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum CellClass {
+    Empty,
+    Integer,
+    Decimal,
+    Alphabetic,
+    Alphanumeric,
+    Other,
+}
+
+const CELL_CLASSES: usize = 6;
+
+impl CellClass {
+    fn of(value: &[u8]) -> Self {
+        if value.is_empty() {
+            return Self::Empty
+        }
+
+        let digits = value.strip_prefix(b"-").unwrap_or(value);
+        if !digits.is_empty() && digits.iter().all(u8::is_ascii_digit) {
+            return Self::Integer
+        }
+        let points = digits.iter().filter(|c| **c == b'.' || **c == b',').count();
+        if points == 1 && digits.iter().all(|c| c.is_ascii_digit() || *c == b'.' || *c == b',') {
+            return Self::Decimal
+        }
+
+        // non-ASCII bytes are letters of UTF-8 or other encoding
+        let is_letter = |c: &u8| c.is_ascii_alphabetic() || *c == b' ' || !c.is_ascii();
+        if value.iter().all(is_letter) {
+            Self::Alphabetic
+        } else if value.iter().all(|c| is_letter(c) || c.is_ascii_digit() || *c == b'_' || *c == b'-') {
+            Self::Alphanumeric
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// Shape of values in one column, collected from rows of table shape
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+struct ColumnStats {
+    values: usize,
+    classes: [usize; CELL_CLASSES],
+    min_len: usize,
+    max_len: usize,
+}
+
+impl ColumnStats {
+    fn add(&mut self, value: &[u8]) {
+        self.classes[CellClass::of(value) as usize] += 1;
+        self.min_len = if self.values == 0 { value.len() } else { min(self.min_len, value.len()) };
+        self.max_len = max(self.max_len, value.len());
+        self.values += 1;
+    }
+
+    /// How unusual `value` is for this column, in thousandths: share
+    /// of values of other classes plus relative length out of range
+    fn cost(&self, value: &[u8]) -> usize {
+        if self.values == 0 {
+            return 0
+        }
+
+        let class_cost = 1000 - 1000 * self.classes[CellClass::of(value) as usize] / self.values;
+        let len = value.len();
+        let len_cost = if len < self.min_len {
+            1000 * (self.min_len - len) / self.min_len
+        } else if len > self.max_len {
+            min(1000, 1000 * (len - self.max_len) / max(self.max_len, 1))
+        } else {
+            0
+        };
+
+        class_cost + len_cost
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub(crate) struct Restorer {
+    field_separator: u8,
+    /// Fields of header, 0 until it's read
+    columns: usize,
+    /// Current line without terminator
+    line: Vec<u8>,
+    fields: Vec<Range<usize>>,
+    stats: Vec<ColumnStats>,
+    /// Lines of ambiguous interval with their terminators
+    interval: Vec<u8>,
+    /// Fields of interval, and whether line ends after the field
+    interval_fields: Vec<(Range<usize>, bool)>,
+    interval_lines: usize,
+}
+
+impl Restorer {
+    pub fn new(field_separator: u8) -> Self {
+        Self {
+            field_separator,
+            ..Default::default()
+        }
+    }
+
+    pub fn process_chunk<S: RowSink>(&mut self, chunk: &[u8], sink: &mut S) -> Result<(), S::Error> {
+        let mut pos = 0;
+        while pos < chunk.len() {
+            let rest = &chunk[pos..];
+            let Some(end) = memchr(b'\n', rest) else {
+                self.line.extend_from_slice(rest);
+                break
+            };
+
+            self.line.extend_from_slice(&rest[..end]);
+            pos += end + 1;
+            self.end_line(sink)?;
+        }
+
+        if self.line.len() > MAX_LINE_BYTES {
+            return sink.malformed("Line too long")
+        }
+        Ok(())
+    }
+
+    /// Last line may have no terminator, pending interval is restored
+    pub fn finish<S: RowSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        if !self.line.is_empty() {
+            self.end_line(sink)?;
+        }
+        self.restore_interval(sink)
+    }
+
+    fn end_line<S: RowSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        let terminator: &[u8] = if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+            b"\r\n"
+        } else {
+            b"\n"
+        };
+        if self.line.len() > MAX_LINE_BYTES {
+            return sink.malformed("Line too long")
+        }
+
+        self.fields.clear();
+        let mut start = 0;
+        for end in memchr_iter(self.field_separator, &self.line) {
+            self.fields.push(start..end);
+            start = end + 1;
+        }
+        self.fields.push(start..self.line.len());
+
+        if self.columns == 0 {
+            if self.fields.len() < 2 {
+                return sink.malformed("Only one column found")
+            }
+            self.columns = self.fields.len();
+            self.stats = vec![ColumnStats::default(); self.columns];
+            sink.row(&self.line, &self.fields, false)?;
+        } else if self.fields.len() == self.columns {
+            self.restore_interval(sink)?;
+            for (stats, field) in self.stats.iter_mut().zip(&self.fields) {
+                stats.add(&self.line[field.clone()]);
+            }
+            sink.row(&self.line, &self.fields, false)?;
+        } else if !self.line.is_empty() || self.interval_lines > 0 {
+            // blank lines are skipped, unless they may be inside value
+            self.push_to_interval(terminator, sink)?;
+        }
+
+        self.line.clear();
+        Ok(())
+    }
+
+    /// Line break inside restored value is kept as it's in the source
+    fn push_to_interval<S: RowSink>(&mut self, terminator: &[u8], sink: &mut S) -> Result<(), S::Error> {
+        if self.interval_lines == MAX_INTERVAL_LINES
+            || self.interval_fields.len() + self.fields.len() > MAX_INTERVAL_FIELDS {
+            return sink.malformed("Ambiguous interval is too long")
+        }
+
+        let base = self.interval.len();
+        self.interval.extend_from_slice(&self.line);
+        self.interval.extend_from_slice(terminator);
+        self.interval_fields.extend(self.fields
+            .iter()
+            .map(|field| (base + field.start..base + field.end, false))
+        );
+        if let Some((_, ends_line)) = self.interval_fields.last_mut() {
+            *ends_line = true;
+        }
+        self.interval_lines += 1;
+        Ok(())
+    }
+
+    fn restore_interval<S: RowSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        // trailing blank lines are not a part of the last value
+        while let [.., (prev, true), (last, true)] = &self.interval_fields[..] {
+            if !last.is_empty() {
+                break
+            }
+            self.interval.truncate(prev.end);
+            self.interval_fields.pop();
+        }
+
+        if !self.interval_fields.is_empty() {
+            let Some(rows) = divide(&self.interval, &self.interval_fields, &self.stats) else {
+                return sink.malformed("Ambiguous interval can't be divided into rows")
+            };
+            for row in rows {
+                sink.row(&self.interval, &row, true)?;
+            }
+        }
+
+        self.interval.clear();
+        self.interval_fields.clear();
+        self.interval_lines = 0;
+        Ok(())
+    }
+}
+
+/// Divides fields of interval into rows of `stats.len()` cells with
+/// minimal total cost, returns cell ranges of every row. Cell takes
+/// consecutive fields, row ends at line break.
+fn divide(interval: &[u8], fields: &[(Range<usize>, bool)], stats: &[ColumnStats]) -> Option<Vec<Vec<Range<usize>>>> {
+    let columns = stats.len();
+    let n = fields.len();
+
+    // for first `i` fields taken and column `j` of the next cell,
+    // `best[i * columns + j]` is minimal cost and first field of the last cell
+    let mut best: Vec<Option<(usize, usize)>> = vec![None; (n + 1) * columns];
+    best[0] = Some((0, 0));
+    for start in 0..n {
+        for column in 0..columns {
+            let Some((cost, _)) = best[start * columns + column] else {
+                continue
+            };
+
+            let is_last_column = column + 1 == columns;
+            for end in start + 1..=min(n, start + MAX_CELL_FIELDS) {
+                // last cell of row ends at line break, others at separator
+                let (_, ends_line) = fields[end - 1];
+                if ends_line != is_last_column {
+                    continue
+                }
+
+                let value = &interval[fields[start].0.start..fields[end - 1].0.end];
+                let cost = cost + stats[column].cost(value) + MERGE_COST * (end - start - 1);
+                let next = &mut best[end * columns + (column + 1) % columns];
+                if next.map_or(true, |(best_cost, _)| cost < best_cost) {
+                    *next = Some((cost, start));
+                }
+            }
+        }
+    }
+
+    best[n * columns]?;
+    let mut rows = vec![];
+    let mut row = vec![];
+    let (mut end, mut column) = (n, 0);
+    while end > 0 {
+        let (_, start) = best[end * columns + column].expect("Path to the end is reachable");
+        column = (column + columns - 1) % columns;
+        row.push(fields[start].0.start..fields[end - 1].0.end);
+        if column == 0 {
+            row.reverse();
+            rows.push(std::mem::take(&mut row));
+        }
+        end = start;
+    }
+
+    rows.reverse();
+    Some(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use super::{divide, ColumnStats, Restorer, RowSink};
+
+    /// Values of id, comment (spaces, different length) and amount
+    const TABLE: [&str; 4] = [
+        "1,nice and quiet place,10",
+        "2,good food but slow service,20",
+        "3,cozy,30",
+        "4,would come again,40",
+    ];
+
+    fn stats() -> Vec<ColumnStats> {
+        let mut stats = vec![ColumnStats::default(); 3];
+        for row in TABLE {
+            for (stats, value) in stats.iter_mut().zip(row.split(',')) {
+                stats.add(value.as_bytes());
+            }
+        }
+        stats
+    }
+
+    /// Divides lines of interval with statistics of `TABLE`
+    fn divide_lines(lines: &[&str]) -> Option<Vec<Vec<String>>> {
+        let interval = lines.join("\n");
+        let mut fields = vec![];
+        let mut start = 0;
+        for line in lines {
+            for value in line.split(',') {
+                fields.push((start..start + value.len(), false));
+                start += value.len() + 1;
+            }
+            fields.last_mut().unwrap().1 = true;
+        }
+
+        let rows = divide(interval.as_bytes(), &fields, &stats())?;
+        Some(rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|cell| interval[cell].to_string()).collect())
+            .collect())
+    }
+
+    /// Repaired rows
+    #[derive(Default)]
+    struct Rows(Vec<Vec<String>>);
+
+    impl RowSink for Rows {
+        type Error = &'static str;
+
+        fn row(&mut self, bytes: &[u8], cells: &[Range<usize>], repaired: bool) -> Result<(), Self::Error> {
+            if repaired {
+                self.0.push(cells
+                    .iter()
+                    .map(|cell| String::from_utf8(bytes[cell.clone()].to_vec()).unwrap())
+                    .collect());
+            }
+            Ok(())
+        }
+
+        fn malformed(&mut self, reason: &'static str) -> Result<(), Self::Error> {
+            Err(reason)
+        }
+    }
+
+    #[test]
+    fn separator_in_value() {
+        assert_eq!(
+            divide_lines(&["5,great view, friendly staff,50"]),
+            Some(vec![vec!["5".into(), "great view, friendly staff".into(), "50".into()]]),
+        );
+    }
+
+    #[test]
+    fn line_break_in_value() {
+        assert_eq!(
+            divide_lines(&["6,first line", "second line,60"]),
+            Some(vec![vec!["6".into(), "first line\nsecond line".into(), "60".into()]]),
+        );
+    }
+
+    #[test]
+    fn rows_of_interval() {
+        assert_eq!(
+            divide_lines(&["7,fast, cheap,70", "8,loud, dark,80"]),
+            Some(vec![
+                vec!["7".into(), "fast, cheap".into(), "70".into()],
+                vec!["8".into(), "loud, dark".into(), "80".into()],
+            ]),
+        );
+    }
+
+    #[test]
+    fn unrestorable_interval() {
+        assert_eq!(divide_lines(&["9,too short"]), None);
+    }
+
+    #[test]
+    fn trailing_blank_lines_with_crlf() {
+        let src = format!("id,comment,amount\r\n{}\r\n10,first line\r\nsecond line,100\r\n\r\n\r\n", TABLE.join("\r\n"));
+        let mut rows = Rows::default();
+        let mut restorer = Restorer::new(b',');
+        restorer.process_chunk(src.as_bytes(), &mut rows).unwrap();
+        restorer.finish(&mut rows).unwrap();
+        assert_eq!(rows.0, [["10", "first line\r\nsecond line", "100"]]);
+    }
+}
@@ -3,27 +3,103 @@
 //! unambiguously (no unescaped/unquoted field/record
 //! separators inside cell values)
 //!
-//! todo! create dialect group for broken csv files, restoring
-//!  rows by detecting ambiguous interval and trying to divide it
-//!  in various ways, determining best by looking up for minimal
-//!  statistic change (min/max col, num/alphanum, enum,
-//!  grex(https://pemistahl.github.io/grex-js/ rules)
-//!
+//! Broken CSV files are restored by `RestoredDialectValidator`
 
 use std::cmp::{min, max};
 use std::string::FromUtf8Error;
-use super::super::{Dialect, DialectGroupValidator};
-use super::{RecordTerminator, SingleByteDialect};
+use crate::asv;
+use super::super::{Dialect, DialectGroupValidator, LineStarts, Tolerance};
+use super::{BlankLines, EscapeScope, MalformedRow, Quoting, RecordTerminator, SingleByteDialect, StrayQuotes};
+use super::tokenizer::{TokenSink, Tokenizer};
 
 
+/// Validates all field separator candidates for one combination
+/// of quote, escape and record terminator: bytes are tokenized
+/// once and every alive candidate gets the same tokens.
 #[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
 pub struct SingleByteDialectValidator {
-    first_row: Vec<Vec<u8>>,
+    tokenizer: Tokenizer,
+    hypotheses: SeparatorHypotheses,
 
-    quote_active: bool,
-    escape_active: bool,
     quote_char: Option<u8>,
+    quoting: Quoting,
+    quote_at_field_start_only: bool,
     escape_char: Option<u8>,
+    escape_scope: EscapeScope,
+    record_terminator: RecordTerminator,
+
+    has_escaped_line_breaks: bool,
+    has_quoted_line_breaks: bool,
+    comment_prefix: Option<Vec<u8>>,
+    /// `COMMENT_PREFIXES` which didn't occur at line start yet
+    unseen_comment_prefixes: Vec<&'static [u8]>,
+
+    current_byte: usize,
+
+    has_headers_user: Option<bool>
+}
+
+/// Alive field separator candidates
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+struct SeparatorHypotheses {
+    alive: Vec<SeparatorHypothesis>,
+    /// Candidates whose separator didn't occur yet are all in the
+    /// same state, so they share it until their separator is found
+    unseen: Vec<u8>,
+    unseen_stats: TableStats,
+    changed: bool,
+    comment_lines: usize,
+    /// Opening quotes, they are the same for all candidates
+    quoted_fields: usize,
+    /// Escape chars, same for all candidates
+    escapes: usize,
+    /// Offset of the current row
+    row_offset: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+struct SeparatorHypothesis {
+    field_separator: u8,
+    field_separator_is_terminator: bool,
+    stats: TableStats,
+}
+
+/// Shape and per-column statistics of parsed table,
+/// updated by whole spans of cell bytes.
+///
+/// Rows of different shape (e.g. title, blank line) at file start are
+/// preamble: table is restarted after them, if there are no more than
+/// `MAX_PREAMBLE_ROWS` and no two of them have the same shape (that's
+/// a table already). Cells of possible first rows are kept for that.
+///
+/// Rows of different shape after at least `MIN_ROWS` rows are footer,
+/// all rows after it must be of different shape too, up to
+/// `MAX_FOOTER_ROWS`. Column statistics are updated when row ends, so
+/// footer doesn't affect them. Summary row of table shape (`Total,,,1234`)
+/// is a data row: it could only be told apart by rows after it.
+///
+/// Blank lines inside table are not rows, they are only counted
+/// (and become preamble rows, if table is restarted after them).
+///
+/// With `tolerance`, footer rows followed by a row of table shape are
+/// malformed rows, and so are footer rows beyond `MAX_FOOTER_ROWS` and
+/// rows which tokenizer can't parse (then footer rows before them too).
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+struct TableStats {
+    tolerance: Tolerance,
+    skip_rows: usize,
+    blank_lines: usize,
+    malformed_rows: Vec<MalformedRow>,
+    first_row: Vec<Vec<u8>>,
+    /// Cells of current row, while it may become the first one
+    row_cells: Vec<Vec<u8>>,
+    /// Statistics of current row cells
+    row_stats: Vec<CellStats>,
+    in_footer: bool,
+    /// Offsets of footer rows
+    footer_rows: Vec<u64>,
+    /// Tokenizer found current row malformed
+    is_row_malformed: bool,
 
     current_cell_is_numeric: bool,
     current_cell_is_ascii: bool,
@@ -33,70 +109,89 @@ pub struct SingleByteDialectValidator {
     col_min_len: Vec<usize>,
     col_max_len: Vec<usize>,
 
-    prev_char_was_cr: bool,
-    record_terminator: RecordTerminator,
-    field_separator_is_terminator: bool,
-
-    field_separator: u8,
-
-    has_escaped_line_breaks: bool,
-    has_quoted_line_breaks: bool,
-
     current_row: usize,
     current_col: usize,
     current_cell_byte: usize,
-    current_byte: usize,
+}
 
-    has_headers_user: Option<bool>
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+struct CellStats {
+    is_ascii: bool,
+    is_numeric: bool,
+    len: usize,
 }
 
 impl DialectGroupValidator for SingleByteDialectValidator {
     fn try_process_chunk(&mut self, chunk: &[u8]) -> Result<(), String> {
-        for (pos, c) in chunk.iter().enumerate() {
-            self.try_process_byte(c)
-                .map_err(|e| self.format_error(e, chunk, pos))?;
-
-            self.current_byte += 1;
+        self.tokenizer
+            .feed(chunk, &mut self.hypotheses)
+            .map_err(|(e, pos)| self.format_error(e, chunk, pos))?;
+
+        // stop looking for separators of failed candidates
+        if self.hypotheses.changed {
+            self.hypotheses.changed = false;
+            let separators: Vec<u8> = self.hypotheses.alive
+                .iter()
+                .map(|h| h.field_separator)
+                .chain(self.hypotheses.unseen.iter().copied())
+                .collect();
+            self.tokenizer.set_separators(&separators);
         }
 
+        self.current_byte += chunk.len();
         Ok(())
     }
 
 
-    fn finalize(&mut self) -> Option<Dialect> {
-        self.check_field_separator_is_terminator();
+    /// Candidates with unseen separator have single column, so they are never valid
+    fn finalize(&mut self) -> Vec<Dialect> {
+        // last row may have no terminator
+        if self.tokenizer.finish(&mut self.hypotheses).is_err() {
+            return vec![]
+        }
 
-        let empty_columns: Vec<bool> = self.col_max_len
-            .iter()
-            .map(|x| *x == 0)
-            .collect();
+        // spawned by false alarm (e.g. line break is quoted)
+        if self.comment_prefix.is_some() && self.hypotheses.comment_lines == 0 {
+            return vec![]
+        }
 
-        let numeric_columns = self.numeric_columns.clone();
+        let mut hypotheses = std::mem::take(&mut self.hypotheses.alive);
+        hypotheses.sort_by_key(|h| SEPARATORS.iter().position(|sep| *sep == h.field_separator));
 
-        // That's either invalid CSV or completely empty file, 
-        // in any case we won't parse it.
-        if empty_columns.iter().all(|x|*x) {
-            return None
+        hypotheses
+            .into_iter()
+            .filter_map(|h| h.finalize(self))
+            .collect()
+    }
+
+    /// Same validator with comment prefix, once line starting with it is found
+    fn spawn(&mut self, lines: &LineStarts) -> Vec<Box<dyn DialectGroupValidator + Send>> {
+        if self.unseen_comment_prefixes.is_empty() {
+            return vec![]
         }
 
-        const MIN_ROWS: usize = 5;
-        if self.current_row < MIN_ROWS {
-            return None;
+        let mut spawned: Vec<Box<dyn DialectGroupValidator + Send>> = vec![];
+        for prefix in std::mem::take(&mut self.unseen_comment_prefixes) {
+            if !lines.has_line_starting_with(self.line_break_end(), prefix) {
+                self.unseen_comment_prefixes.push(prefix);
+                continue
+            }
+
+            let mut v = self.clone();
+            v.comment_prefix = Some(prefix.to_vec());
+            v.unseen_comment_prefixes.clear();
+            v.tokenizer.set_comment_prefix(v.comment_prefix.clone());
+            spawned.push(Box::new(v));
         }
 
-        Some(Dialect::SingleByte(SingleByteDialect {
-            header: self.try_get_headers(),
-            field_separator: self.field_separator,
-            quote_char: self.quote_char,
-            escape_char: self.escape_char,
-            empty_columns,
-            numeric_columns,
-            record_terminator: self.record_terminator.clone(),
-            field_separator_is_terminator: self.field_separator_is_terminator,
-            has_escaped_line_breaks: self.has_escaped_line_breaks,
-            has_quoted_line_breaks: self.has_quoted_line_breaks,
-            total_rows: self.current_row,
-        }))
+        spawned
+    }
+
+    fn set_tolerance(&mut self, tolerance: Tolerance) {
+        self.hypotheses.unseen_stats.tolerance = tolerance;
+        for h in &mut self.hypotheses.alive {
+            h.stats.tolerance = tolerance;
+        }
     }
 }
 
@@ -122,8 +217,16 @@ impl SingleByteDialectValidator {
     //
     //     variants
     // }
-    
-    #[allow(clippy::single_element_loop)]
+
+    /// One validator per quote/escape/terminator combination, each checks
+    /// all `SEPARATORS`. `quoting`, `quote_at_field_start_only` (implied by
+    /// RFC 4180 quoting) and `has_quoted_line_breaks` are enumerated only
+    /// when there is quote char, otherwise they change nothing, same for
+    /// `escape_scope` without both quote and escape chars. Stray quotes
+    /// are literal, as rejecting them never changes the output.
+    ///
+    /// Validators with `comment_prefix` are spawned only when any line
+    /// starts with one of `COMMENT_PREFIXES`.
     pub fn make(has_headers: Option<bool>) -> Vec<Self> {
         let mut variants = vec![SingleByteDialectValidator {
             has_headers_user: has_headers,
@@ -131,242 +234,286 @@ impl SingleByteDialectValidator {
         }];
 
         for mut v in variants.clone().into_iter() {
-            v.has_quoted_line_breaks = true;
+            v.escape_char = Some(b'\\');
             variants.push(v);
         }
 
         for mut v in variants.clone().into_iter() {
-            v.has_quoted_line_breaks = true;
-            variants.push(v);
+            for q in [b'"', b'\''] {
+                v.quote_char = Some(q);
+                variants.push(v.clone());
+            }
         }
 
         for mut v in variants.clone().into_iter() {
-            v.escape_char = Some(b'\\');
-            variants.push(v);
+            if v.quote_char.is_some() {
+                v.quoting = Quoting::Rfc4180(StrayQuotes::Literal);
+                variants.push(v.clone());
+
+                v.quoting = Quoting::Toggle;
+                v.quote_at_field_start_only = true;
+                variants.push(v);
+            }
         }
 
         for mut v in variants.clone().into_iter() {
-            for q in [b'"', b'\''] {
-                v.quote_char = Some(q);
-                variants.push(v.clone());
+            if v.quote_char.is_some() && v.escape_char.is_some() {
+                v.escape_scope = EscapeScope::InsideQuotes;
+                variants.push(v);
             }
         }
 
         for mut v in variants.clone().into_iter() {
-            for q in [b'\t', b',', b';', b'|', b':'] {
-                v.field_separator = q;
-                variants.push(v.clone());
+            if v.quote_char.is_some() {
+                v.has_quoted_line_breaks = true;
+                variants.push(v);
             }
         }
 
         for mut v in variants.clone().into_iter() {
-            for q in [RecordTerminator::Byte(b'\n')] {
+            for q in [RecordTerminator::Byte(b'\n'), RecordTerminator::Byte(asv::RECORD_SEPARATOR)] {
                 v.record_terminator = q;
                 variants.push(v.clone());
             }
         }
 
         for v in &mut variants {
-            v.push_first_row_cell();
+            v.tokenizer = Tokenizer::with_separators(&SingleByteDialect {
+                quote_char: v.quote_char,
+                quoting: v.quoting,
+                quote_at_field_start_only: v.quote_at_field_start_only,
+                escape_char: v.escape_char,
+                escape_scope: v.escape_scope,
+                record_terminator: v.record_terminator.clone(),
+                has_escaped_line_breaks: v.has_escaped_line_breaks,
+                has_quoted_line_breaks: v.has_quoted_line_breaks,
+                ..Default::default()
+            }, &SEPARATORS);
+
+            v.hypotheses.unseen = SEPARATORS.to_vec();
+            v.hypotheses.unseen_stats.push_first_row_cell();
+            v.unseen_comment_prefixes = COMMENT_PREFIXES.to_vec();
         }
 
         variants
     }
 
-    #[inline]
-    fn try_process_byte(&mut self, c: &u8) -> Result<(), &'static str> {
-        // these try_* functions returns true if byte is accepted/consumed
-        if self.try_escape(c)? {
-            if !self.has_escaped_line_breaks {
-                self.try_next_row(c)?;
-            }
-            return Ok(());
+    /// Last byte of record terminator
+    fn line_break_end(&self) -> u8 {
+        match self.record_terminator {
+            RecordTerminator::Crlf => b'\n',
+            RecordTerminator::Byte(t) => t,
         }
+    }
+
+    fn format_error(&self, desc: String, buffer: &[u8], pos: usize) -> String {
+        const CONTEXT_SIZE: usize = 256;
 
-        if self.try_quote(c)? {
-            if !self.has_quoted_line_breaks {
-                self.try_next_row(c)?;
+        let ctx_min = max(0, pos.clamp(CONTEXT_SIZE, usize::MAX) - CONTEXT_SIZE);
+        let ctx_max = min(buffer.len() - 1, pos + CONTEXT_SIZE);
+        let context = String::from_utf8_lossy(&buffer[ctx_min..ctx_max]);
+        format!("{desc} (offset={}) near `{context}`", self.current_byte + pos)
+    }
+}
+
+
+impl SeparatorHypotheses {
+    /// Applies `f` to every candidate, failed ones are dropped.
+    /// Fails when no candidates left.
+    /// `f` gets separator of candidate, `None` for shared state of unseen ones.
+    #[inline]
+    fn retain(&mut self, mut f: impl FnMut(Option<u8>, &mut TableStats) -> Result<(), &'static str>) -> Result<(), String> {
+        let mut error = None;
+        if !self.unseen.is_empty() {
+            if let Err(e) = f(None, &mut self.unseen_stats) {
+                error = Some(format!(
+                    "{e} at {}:{} (separators {:x?})",
+                    self.unseen_stats.current_row, self.unseen_stats.current_col, self.unseen
+                ));
+                self.unseen.clear();
+                self.unseen_stats = TableStats::default();
             }
-            return Ok(());
         }
 
-        if self.try_next_row(c)? {
-            return Ok(());
-        }
+        self.alive.retain_mut(|h| match f(Some(h.field_separator), &mut h.stats) {
+            Ok(()) => true,
+            Err(e) => {
+                error = Some(format!(
+                    "{e} at {}:{} (separator 0x{:x})",
+                    h.stats.current_row, h.stats.current_col, h.field_separator
+                ));
+                false
+            }
+        });
 
-        if self.try_next_field(c)? {
-            return Ok(());
+        match error {
+            None => Ok(()),
+            Some(e) => {
+                self.changed = true;
+                if self.alive.is_empty() && self.unseen.is_empty() { Err(e) } else { Ok(()) }
+            }
         }
+    }
 
-        self.try_next_char(c)?;
-        Ok(())
+    /// Splits candidate from shared state on first occurrence of its separator
+    #[cold]
+    fn fork_unseen(&mut self, separator: u8) {
+        let Some(i) = self.unseen.iter().position(|sep| *sep == separator) else {
+            return
+        };
+
+        self.unseen.remove(i);
+        let stats = if self.unseen.is_empty() {
+            std::mem::take(&mut self.unseen_stats)
+        } else {
+            self.unseen_stats.clone()
+        };
+
+        self.alive.push(SeparatorHypothesis {
+            field_separator: separator,
+            field_separator_is_terminator: false,
+            stats,
+        });
     }
+}
+
+impl TokenSink for SeparatorHypotheses {
+    type Error = String;
 
     #[inline]
-    fn try_escape(&mut self, c: &u8) -> Result<bool, &'static str> {
-        if self.escape_active {
-            self.escape_active = false;
-            return Ok(true);
-        }
-
-        if let Some(e) = self.escape_char {
-            if *c == e {
-                self.escape_active = true;
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        } else {
-            Ok(false)
-        }
+    fn data(&mut self, span: &[u8]) -> Result<(), String> {
+        let is_numeric = span.iter().all(u8::is_ascii_digit);
+        let is_ascii = span.is_ascii();
+        self.retain(|_, stats| stats.push_chars(span, is_numeric, is_ascii))
     }
 
+    /// Quoted and escaped bytes are not taken into account
     #[inline]
-    fn try_quote(&mut self, c: &u8) -> Result<bool, &'static str>  {
-        if let Some(q) = self.quote_char {
-            let was_active = self.quote_active;
-            // switch if current char is quote
-            self.quote_active ^= q == *c;
-            Ok(was_active)
-        } else {
-            Ok(false)
-        }
+    fn literal(&mut self, _span: &[u8]) -> Result<(), String> {
+        Ok(())
     }
 
+    /// Opening quote is counted as a cell byte
     #[inline]
-    fn try_next_row(&mut self, c: &u8) -> Result<bool, &'static str> {
-        match &self.record_terminator {
-            RecordTerminator::Byte(t) => {
-                if c == t {
-                    self.end_row()?;
-                    Ok(true)
-                } else {
-                    Ok(false)
-                }
-            }
-            RecordTerminator::Crlf => {
-                if *c == b'\r' {
-                    self.prev_char_was_cr = true;
-                    Ok(true)
-                } else if *c == b'\n' && self.prev_char_was_cr {
-                    self.prev_char_was_cr = false;
-                    self.end_row()?;
-                    Ok(true)
-                } else {
-                    self.prev_char_was_cr = false;
-                    Ok(false)
-                }
-            }
-        }
+    fn open_quote(&mut self, quote: u8) -> Result<(), String> {
+        self.quoted_fields += 1;
+        self.data(&[quote])
     }
 
+    /// Escaped byte is a literal, so only the escape is counted
     #[inline]
-    fn try_next_field(&mut self, c: &u8) -> Result<bool, &'static str> {
-        if *c == self.field_separator {
-            self.end_field()?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    fn escape(&mut self, _escape: u8) -> Result<(), String> {
+        self.escapes += 1;
+        Ok(())
     }
 
+    /// Separator of one candidate is a cell byte for others
     #[inline]
-    fn try_next_char(&mut self, c: &u8) -> Result<(), &'static str> {
-        if self.current_row == 0 {
-            self.push_first_row_char(c);
+    fn separator(&mut self, separator: u8) -> Result<(), String> {
+        if self.unseen.contains(&separator) {
+            self.fork_unseen(separator);
         }
-        self.current_cell_is_numeric &= c.is_ascii_digit();
-        self.current_cell_is_ascii &= c.is_ascii();
-        self.current_cell_byte += 1;
-        const MAX_FIELD_BYTES: usize = 1024 * 1024 * 10; // 10 MiB
-        if self.current_cell_byte > MAX_FIELD_BYTES {
-            Err("Cell value too long")
+
+        let is_ascii = separator.is_ascii();
+        self.retain(|sep, stats| if sep == Some(separator) {
+            stats.end_field()
         } else {
-            Ok(())
-        }
+            stats.push_chars(&[separator], false, is_ascii)
+        })
     }
 
     #[inline]
-    fn end_field(&mut self) -> Result<(), &'static str>  {
-        const MAX_COLUMNS: usize = 5000;
+    fn end_row(&mut self, next_row_offset: u64) -> Result<(), String> {
+        let row_offset = std::mem::replace(&mut self.row_offset, next_row_offset);
+        self.retain(|_, stats| stats.end_row(row_offset))
+    }
 
-        if self.current_row != 0 {
-            if self.current_col == self.ascii_columns.len() {
-                return Err("Inconsistent row length")
-            }
+    #[cold]
+    fn blank_line(&mut self, next_row_offset: u64) -> Result<(), String> {
+        let row_offset = std::mem::replace(&mut self.row_offset, next_row_offset);
+        self.retain(|_, stats| stats.blank_line(row_offset))
+    }
 
-            self.ascii_columns[self.current_col] &= self.current_cell_is_ascii;
-            self.numeric_columns[self.current_col] &= self.current_cell_is_numeric;
-            self.col_min_len[self.current_col] = min(self.col_min_len[self.current_col], self.current_cell_byte);
-            self.col_max_len[self.current_col] = max(self.col_max_len[self.current_col], self.current_cell_byte);
-        } else {
-            self.push_first_row_cell();
-            if self.current_col > MAX_COLUMNS {
-                return Err("Too many columns (first row)")
-            }
-        }
+    /// Current row is malformed for all candidates, as they share quoting
+    #[cold]
+    fn malformed(&mut self, reason: &'static str) -> Result<(), String> {
+        self.retain(|_, stats| stats.malformed_row(reason))
+    }
 
-        self.quote_active = false;
-        self.escape_active = false;
-        self.current_cell_is_ascii = true;
-        self.current_cell_is_numeric = true;
-        self.current_cell_byte = 0;
-        self.current_col += 1;
+    /// Comment lines are not taken into account
+    #[inline]
+    fn comment(&mut self, _span: &[u8]) -> Result<(), String> {
         Ok(())
     }
 
     #[inline]
-    fn end_row(&mut self) -> Result<(), &'static str> {
-        if self.current_row != 0 && self.current_col != self.first_row.len() - 1 {
-            return Err("Inconsistent row length (missing column)")
-        }
-        if self.current_col == 0 {
-            return Err("Only one column found")
-        }
+    fn end_comment(&mut self, next_row_offset: u64) -> Result<(), String> {
+        self.comment_lines += 1;
+        self.row_offset = next_row_offset;
+        Ok(())
+    }
+}
 
-        self.end_field()?;
-        // .end_field() always starts new column
-        if self.current_row == 0 {
-            self.pop_first_row_cell();
-        }
 
-        self.prev_char_was_cr = false;
-        self.current_col = 0;
-        self.current_row += 1;
+impl SeparatorHypothesis {
+    fn finalize(mut self, group: &SingleByteDialectValidator) -> Option<Dialect> {
+        self.check_field_separator_is_terminator();
 
-        Ok(())
-    }
+        let empty_columns: Vec<bool> = self.stats.col_max_len
+            .iter()
+            .map(|x| *x == 0)
+            .collect();
 
-    #[cold]
-    fn push_first_row_char(&mut self, c: &u8) {
-        self.first_row[self.current_col].push(*c);
-    }
+        let numeric_columns = self.stats.numeric_columns.clone();
 
-    #[cold]
-    fn push_first_row_cell(&mut self) {
-        self.first_row.push(vec![]);
-        self.col_min_len.push(usize::MAX);
-        self.col_max_len.push(usize::MIN);
-        self.ascii_columns.push(true);
-        self.numeric_columns.push(true);
-    }
+        // That's either invalid CSV or completely empty file, 
+        // in any case we won't parse it.
+        if empty_columns.iter().all(|x|*x) {
+            return None
+        }
 
-    #[cold]
-    fn pop_first_row_cell(&mut self) {
-        self.first_row.pop();
-        self.col_min_len.pop();
-        self.col_max_len.pop();
-        self.ascii_columns.pop();
-        self.numeric_columns.pop();
+        if self.stats.current_row < MIN_ROWS || !self.stats.is_tolerated() {
+            return None;
+        }
+
+        let header = self.try_get_headers(group.has_headers_user);
+        let has_placeholder_header = header.is_none() && self.is_placeholder_header(group.has_headers_user);
+
+        Some(Dialect::SingleByte(SingleByteDialect {
+            header,
+            has_placeholder_header,
+            field_separator: self.field_separator,
+            quote_char: group.quote_char,
+            quoting: group.quoting,
+            quote_at_field_start_only: group.quote_at_field_start_only,
+            escape_char: group.escape_char,
+            escape_scope: group.escape_scope,
+            empty_columns,
+            numeric_columns,
+            record_terminator: group.record_terminator.clone(),
+            comment_prefix: group.comment_prefix.clone(),
+            field_separator_is_terminator: self.field_separator_is_terminator,
+            has_escaped_line_breaks: group.has_escaped_line_breaks,
+            has_quoted_line_breaks: group.has_quoted_line_breaks,
+            total_rows: self.stats.current_row,
+            quoted_fields: group.hypotheses.quoted_fields,
+            escapes: group.hypotheses.escapes,
+            skip_rows: self.stats.skip_rows,
+            skip_footer_rows: self.stats.footer_rows.len(),
+            blank_lines: if self.stats.blank_lines > 0 { BlankLines::Skip } else { BlankLines::Reject },
+            malformed_rows: self.stats.malformed_rows,
+            encoding: None,
+            bom: None,
+        }))
     }
 
     fn check_field_separator_is_terminator(&mut self) {
-        let last_col_is_empty = *self.col_max_len.last().unwrap() == 0;
-        let last_col_name_is_empty = self.first_row.last().unwrap().is_empty();
+        let last_col_is_empty = *self.stats.col_max_len.last().unwrap() == 0;
+        let last_col_name_is_empty = self.stats.first_row.last().unwrap().is_empty();
 
         if last_col_is_empty && last_col_name_is_empty {
             self.field_separator_is_terminator = true;
-            self.pop_first_row_cell();
+            self.stats.pop_first_row_cell();
         }
     }
 
@@ -378,19 +525,23 @@ impl SingleByteDialectValidator {
     /// - first line value is non-numeric, but all other values are
     /// - first value is non-ascii but all other values are
     ///   (e.g. header is non-english)
-    fn try_get_headers(&self) -> Option<Vec<String>> {
-        let first_row = self.first_row
+    fn try_get_headers(&self, has_headers_user: Option<bool>) -> Option<Vec<String>> {
+        let first_row = self.stats.first_row
             .iter()
             .map(|x| String::from_utf8(x.clone()))
             .collect::<Result<Vec<String>, FromUtf8Error>>()
             .ok();
 
         if let Some(header) = first_row {
-            if self.has_headers_user == Some(true) {
+            if has_headers_user == Some(true) {
                 return Some(header);
             }
 
-            if self.has_headers_user == Some(false) {
+            if has_headers_user == Some(false) {
+                return None;
+            }
+
+            if self.is_placeholder_header(has_headers_user) {
                 return None;
             }
 
@@ -416,8 +567,8 @@ impl SingleByteDialectValidator {
             }
 
             for col_id in 0..header.len() {
-                let col_min = self.col_min_len[col_id];
-                let col_max = self.col_max_len[col_id];
+                let col_min = self.stats.col_min_len[col_id];
+                let col_max = self.stats.col_max_len[col_id];
                 let col_header_len = header[col_id].len();
 
                 if !(col_min..=col_max).contains(&col_header_len) {
@@ -425,13 +576,13 @@ impl SingleByteDialectValidator {
                 }
             }
 
-            for (col_id, is_ascii) in self.ascii_columns.iter().enumerate() {
-                if *is_ascii && !self.first_row[col_id].is_ascii() {
+            for (col_id, is_ascii) in self.stats.ascii_columns.iter().enumerate() {
+                if *is_ascii && !self.stats.first_row[col_id].is_ascii() {
                     return Some(header)
                 }
             }
 
-            for (col_id, is_numeric) in self.numeric_columns.iter().enumerate() {
+            for (col_id, is_numeric) in self.stats.numeric_columns.iter().enumerate() {
                 if *is_numeric && !header[col_id].chars().all(|c|c.is_ascii_digit()) {
                     return Some(header)
                 }
@@ -441,18 +592,310 @@ impl SingleByteDialectValidator {
         None
     }
     
-    fn format_error(&self, desc: &'static str, buffer: &[u8], pos: usize) -> String {
-        const CONTEXT_SIZE: usize = 256;
+    /// First row is `__NO_HEADER__` placeholders, as in our own ASV output
+    fn is_placeholder_header(&self, has_headers_user: Option<bool>) -> bool {
+        has_headers_user != Some(false) && self.stats.first_row
+            .iter()
+            .all(|x| x == asv::NO_HEADER.as_bytes())
+    }
+}
 
-        let ctx_min = max(0, pos.clamp(CONTEXT_SIZE, usize::MAX) - CONTEXT_SIZE);
-        let ctx_max = min(buffer.len() - 1, pos + CONTEXT_SIZE);
-        let context = String::from_utf8_lossy(&buffer[ctx_min..ctx_max]);
-        format!("{desc} at {}:{} (offset={}) near `{context}`", self.current_row, self.current_col, self.current_byte)
+
+impl TableStats {
+    #[inline]
+    fn push_chars(&mut self, span: &[u8], is_numeric: bool, is_ascii: bool) -> Result<(), &'static str> {
+        if self.current_row + self.skip_rows <= MAX_PREAMBLE_ROWS {
+            self.push_first_row_chars(span);
+        }
+        self.current_cell_is_numeric &= is_numeric;
+        self.current_cell_is_ascii &= is_ascii;
+        self.current_cell_byte += span.len();
+        const MAX_FIELD_BYTES: usize = 1024 * 1024 * 10; // 10 MiB
+        if self.current_cell_byte > MAX_FIELD_BYTES {
+            Err("Cell value too long")
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cold]
+    fn push_first_row_chars(&mut self, span: &[u8]) {
+        if self.current_row == 0 {
+            self.first_row[self.current_col].extend_from_slice(span);
+            return
+        }
+
+        if self.row_cells.len() <= self.current_col {
+            self.row_cells.resize(self.current_col + 1, vec![]);
+        }
+        self.row_cells[self.current_col].extend_from_slice(span);
+    }
+
+    /// Row of different shape can be the first one after preamble, if
+    /// previous rows can all be preamble: the first row of table so far
+    /// is the only one of its shape, other rows were preamble already
+    #[inline]
+    fn can_skip_preamble(&self, rows: usize) -> bool {
+        self.current_row < 2 && self.skip_rows + rows <= MAX_PREAMBLE_ROWS
+    }
+
+    /// Current row has different shape: it's either the first row after
+    /// preamble (which is narrower than table) or the first footer row
+    /// (which is preceded by at least `MIN_ROWS` rows, unless malformed
+    /// rows are tolerated)
+    #[cold]
+    fn shape_changed(&mut self, is_wider: bool) -> Result<(), &'static str> {
+        if is_wider && self.can_skip_preamble(self.current_row) {
+            self.restart_from_current_row();
+            return Ok(())
+        }
+
+        if self.current_row < MIN_ROWS && self.tolerance == Tolerance::Strict {
+            return Err(if is_wider { "Inconsistent row length" } else { "Inconsistent row length (missing column)" })
+        }
+        self.in_footer = true;
+        self.row_stats.clear();
+        Ok(())
+    }
+
+    /// Blank line before the first row is preamble
+    #[cold]
+    fn blank_line(&mut self, row_offset: u64) -> Result<(), &'static str> {
+        if self.current_row == 0 {
+            return self.end_row(row_offset)
+        }
+
+        self.blank_lines += 1;
+        Ok(())
+    }
+
+    #[cold]
+    fn end_footer_row(&mut self, row_offset: u64) -> Result<(), &'static str> {
+        if self.footer_rows.len() == MAX_FOOTER_ROWS {
+            if self.tolerance == Tolerance::Strict {
+                return Err("Inconsistent row length (too many footer rows)")
+            }
+            self.quarantine_footer()?;
+        }
+
+        self.footer_rows.push(row_offset);
+        self.row_stats.clear();
+        self.current_cell_byte = 0;
+        self.current_col = 0;
+        Ok(())
+    }
+
+    /// Footer rows turned out to be inside table, so they are malformed rows
+    #[cold]
+    fn quarantine_footer(&mut self) -> Result<(), &'static str> {
+        let row = self.current_row;
+        self.malformed_rows.extend(self.footer_rows.drain(..).map(|offset| MalformedRow { row, offset }));
+
+        let too_many = match self.tolerance {
+            Tolerance::Strict => true,
+            Tolerance::Records(max) => self.malformed_rows.len() > max,
+            // final share is checked by `is_tolerated`, more malformed
+            // rows than well-formed ones is a wrong dialect anyway
+            Tolerance::PartsPerMillion(_) => self.malformed_rows.len() > self.current_row,
+        };
+        if too_many {
+            return Err("Inconsistent row length (too many malformed rows)")
+        }
+        Ok(())
+    }
+
+    /// Tokenizer can't parse current row, it's skipped up to terminator
+    #[cold]
+    fn malformed_row(&mut self, reason: &'static str) -> Result<(), &'static str> {
+        if self.tolerance == Tolerance::Strict || self.current_row == 0 {
+            return Err(reason)
+        }
+        self.is_row_malformed = true;
+        Ok(())
+    }
+
+    /// Malformed row is quarantined with footer rows before it, which
+    /// are inside table then
+    #[cold]
+    fn end_malformed_row(&mut self, row_offset: u64) -> Result<(), &'static str> {
+        self.is_row_malformed = false;
+        self.in_footer = false;
+        self.footer_rows.push(row_offset);
+        self.quarantine_footer()?;
+
+        self.row_stats.clear();
+        self.row_cells.clear();
+        self.current_cell_is_ascii = true;
+        self.current_cell_is_numeric = true;
+        self.current_cell_byte = 0;
+        self.current_col = 0;
+        Ok(())
+    }
+
+    /// Share of malformed rows is within tolerance
+    fn is_tolerated(&self) -> bool {
+        let Tolerance::PartsPerMillion(max) = self.tolerance else {
+            return true
+        };
+
+        let malformed = self.malformed_rows.len() as u64;
+        let total = self.current_row as u64 + malformed;
+        malformed * 1_000_000 <= max as u64 * total
+    }
+
+    #[inline]
+    fn apply_row_stats(&mut self) {
+        for (col, cell) in self.row_stats.iter().enumerate() {
+            self.ascii_columns[col] &= cell.is_ascii;
+            self.numeric_columns[col] &= cell.is_numeric;
+            self.col_min_len[col] = min(self.col_min_len[col], cell.len);
+            self.col_max_len[col] = max(self.col_max_len[col], cell.len);
+        }
+        self.row_stats.clear();
+    }
+
+    /// Restarts table from the current row, which is in the middle of
+    /// `current_col` cell. Previous rows are preamble.
+    #[cold]
+    fn restart_from_current_row(&mut self) {
+        let mut first_row = std::mem::take(&mut self.row_cells);
+        first_row.resize(self.current_col + 1, vec![]);
+
+        *self = TableStats {
+            tolerance: self.tolerance,
+            skip_rows: self.skip_rows + self.current_row + self.blank_lines,
+            current_col: self.current_col,
+            current_cell_byte: self.current_cell_byte,
+            ..Default::default()
+        };
+        for cell in first_row {
+            self.push_first_row_cell();
+            *self.first_row.last_mut().unwrap() = cell;
+        }
+    }
+
+    /// Restarts table from the next row, current and previous rows are preamble
+    #[cold]
+    fn restart_from_next_row(&mut self) {
+        *self = TableStats {
+            tolerance: self.tolerance,
+            skip_rows: self.skip_rows + self.current_row + self.blank_lines + 1,
+            ..Default::default()
+        };
+        self.push_first_row_cell();
+    }
+
+    #[cold]
+    fn push_first_row_cell(&mut self) {
+        self.first_row.push(vec![]);
+        self.col_min_len.push(usize::MAX);
+        self.col_max_len.push(usize::MIN);
+        self.ascii_columns.push(true);
+        self.numeric_columns.push(true);
+    }
+
+    #[cold]
+    fn pop_first_row_cell(&mut self) {
+        self.first_row.pop();
+        self.col_min_len.pop();
+        self.col_max_len.pop();
+        self.ascii_columns.pop();
+        self.numeric_columns.pop();
+    }
+
+    #[inline]
+    fn end_field(&mut self) -> Result<(), &'static str>  {
+        const MAX_COLUMNS: usize = 5000;
+
+        if self.current_row != 0 && self.current_col == self.ascii_columns.len() && !self.in_footer {
+            self.shape_changed(true)?;
+        }
+
+        if self.in_footer && self.current_col >= self.ascii_columns.len() {
+            // not taken into account, unless row turns out to be well-formed
+        } else if self.current_row != 0 {
+            self.row_stats.push(CellStats {
+                is_ascii: self.current_cell_is_ascii,
+                is_numeric: self.current_cell_is_numeric,
+                len: self.current_cell_byte,
+            });
+        } else {
+            self.push_first_row_cell();
+            if self.current_col > MAX_COLUMNS {
+                return Err("Too many columns (first row)")
+            }
+        }
+
+        self.current_cell_is_ascii = true;
+        self.current_cell_is_numeric = true;
+        self.current_cell_byte = 0;
+        self.current_col += 1;
+        Ok(())
+    }
+
+    #[inline]
+    fn end_row(&mut self, row_offset: u64) -> Result<(), &'static str> {
+        if self.is_row_malformed {
+            return self.end_malformed_row(row_offset)
+        }
+        if self.in_footer {
+            // footer rows all differ from table shape
+            if self.current_col != self.first_row.len() - 1 {
+                return self.end_footer_row(row_offset)
+            }
+            if self.tolerance == Tolerance::Strict {
+                return Err("Inconsistent row length (row of table shape after footer)")
+            }
+            self.quarantine_footer()?;
+            self.in_footer = false;
+        }
+        if self.current_col == 0 {
+            if self.can_skip_preamble(self.current_row + 1) {
+                self.restart_from_next_row();
+                return Ok(())
+            }
+            if self.current_row == 0 {
+                return Err("Only one column found")
+            }
+            self.shape_changed(false)?;
+            return self.end_footer_row(row_offset)
+        }
+        if self.current_row != 0 && self.current_col != self.first_row.len() - 1 {
+            self.shape_changed(self.current_col >= self.first_row.len())?;
+            if self.in_footer {
+                return self.end_footer_row(row_offset)
+            }
+        }
+
+        self.end_field()?;
+        // .end_field() always starts new column
+        if self.current_row == 0 {
+            self.pop_first_row_cell();
+        } else {
+            self.apply_row_stats();
+        }
+
+        self.current_col = 0;
+        self.current_row += 1;
+        self.row_cells.clear();
+
+        Ok(())
     }
 }
 
 
-const KNOWN_HEADERS: &'static [&'static str; 25] = &[
+const SEPARATORS: [u8; 6] = [b'\t', b',', b';', b'|', b':', asv::FIELD_SEPARATOR];
+
+/// Not longer than `MAX_LINE_PREFIX_LEN`
+const COMMENT_PREFIXES: [&[u8]; 2] = [b"#", b"//"];
+
+const MIN_ROWS: usize = 5;
+
+const MAX_PREAMBLE_ROWS: usize = 10;
+
+const MAX_FOOTER_ROWS: usize = 10;
+
+const KNOWN_HEADERS: &[&str; 25] = &[
     "email",
     "id",
     "full_name",
@@ -479,3 +922,140 @@ const KNOWN_HEADERS: &'static [&'static str; 25] = &[
     "work",
     "телефон"
 ];
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::{BlankLines, CsvSniffer, Dialect, MalformedRow, NormalizeOptions, QuarantineSink, RecordTerminator, SingleByteDialect, Tolerance};
+    use super::super::generator::sniff;
+
+    fn dialects(src: &str) -> Vec<SingleByteDialect> {
+        tolerant_dialects(src, Tolerance::Strict)
+    }
+
+    fn tolerant_dialects(src: &str, tolerance: Tolerance) -> Vec<SingleByteDialect> {
+        let mut sniffer = CsvSniffer::new(None).with_tolerance(tolerance);
+        sniffer.process(&mut src.as_bytes());
+        sniffer.dialects()
+            .into_iter()
+            .filter_map(|d| match d {
+                Dialect::SingleByte(d) => Some(d),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn rows(from: usize, to: usize) -> String {
+        let names = ["ann", "bob", "carl", "dan", "eve", "fay", "gus", "hal", "ivy", "jon", "kim", "lee"];
+        (from..=to).map(|i| format!("{i},{},{}\n", names[(i - 1) % names.len()], i * 10 + 10)).collect()
+    }
+
+    #[test]
+    fn preamble() {
+        let src = format!("Monthly report\nGenerated on 2024-01-31\n\nid,name,age\n{}", rows(1, 6));
+        let best = dialects(&src).into_iter().max().unwrap();
+        assert_eq!(best.skip_rows, 3);
+        assert_eq!(best.header, Some(vec!["id".into(), "name".into(), "age".into()]));
+    }
+
+    #[test]
+    fn footer() {
+        let src = format!("id,name,age\n{}Total 6\n(6 rows)\n", rows(1, 6));
+        let best = dialects(&src).into_iter().max().unwrap();
+        assert_eq!(best.skip_footer_rows, 2);
+        assert_eq!(best.total_rows, 7);
+    }
+
+    /// Summary row of table shape is not footer, but the last data row
+    /// (which makes its key column non-numeric)
+    #[test]
+    fn footer_of_table_shape() {
+        let src = format!("id,name,age\n{}Total,,210\n", rows(1, 6));
+        let best = dialects(&src).into_iter().max().unwrap();
+        assert_eq!((best.skip_footer_rows, best.total_rows), (0, 8));
+        assert_eq!(best.numeric_columns, [false, false, true]);
+    }
+
+    /// Rows of table shape after a wider row are not footer
+    #[test]
+    fn wider_row_before_end() {
+        let src = format!("id,name,age\n{}6,fay,70,extra\n{}", rows(1, 5), rows(7, 8));
+        assert!(dialects(&src).iter().all(|d| d.skip_footer_rows == 0), "{:?}", dialects(&src));
+    }
+
+    /// Rows of table shape before a wider row are not preamble
+    #[test]
+    fn wider_row_inside_table() {
+        let src = format!("id,name,age\n{}3,carl,x,40\n{}", rows(1, 2), rows(4, 12));
+        assert!(dialects(&src).iter().all(|d| d.skip_rows == 0), "{:?}", dialects(&src));
+    }
+
+    /// Rows which tokenizer can't parse are tolerated like rows of different shape
+    #[test]
+    fn unterminated_quote_is_malformed_row() {
+        let rows: String = (1..=8).map(|i| format!("{i},\"name {i}\",{}\n", i * 10)).collect();
+        let (before, after) = rows.split_at(rows.find("3,").unwrap());
+        let src = format!("id,name,age\n{before}3,\"carl, jr,40\n{after}");
+        assert!(dialects(&src).iter().all(|d| d.quote_char != Some(b'"') || d.has_quoted_line_breaks));
+
+        let best = tolerant_dialects(&src, Tolerance::Records(1))
+            .into_iter()
+            .filter(|d| d.quote_char == Some(b'"') && !d.has_quoted_line_breaks)
+            .max()
+            .unwrap();
+        assert_eq!(best.malformed_rows, [MalformedRow { row: 3, offset: src.find("3,").unwrap() as u64 }]);
+
+        let quarantine = Arc::new(Mutex::new(vec![]));
+        let options = NormalizeOptions { quarantine: Some(QuarantineSink(quarantine.clone())), ..Default::default() };
+        let mut asv = vec![];
+        Dialect::SingleByte(best).to_asv_with_options(src.as_bytes(), &mut asv, &options).unwrap();
+        assert_eq!(*quarantine.lock().unwrap(), b"3,\"carl, jr,40\n");
+        assert_eq!(asv.iter().filter(|c| **c == crate::asv::RECORD_SEPARATOR).count(), 9);
+    }
+
+    /// Comment lines are not rows, also at file start and with
+    /// prefix split between chunks
+    #[test]
+    fn comment_lines() {
+        let src = format!("# exported\nid,name,age\n{}# half\n{}", rows(1, 3), rows(4, 6));
+        let best = dialects(&src).into_iter().max().unwrap();
+        assert_eq!(best.comment_prefix, Some(b"#".to_vec()));
+        assert_eq!((best.skip_rows, best.total_rows), (0, 7));
+
+        let src = format!("id,name,age\r\n{}// half\r\n{}", rows(1, 3), rows(4, 6)).replace("0\n", "0\r\n");
+        for chunk_size in [src.find("//").unwrap() + 1, src.len()] {
+            let Dialect::SingleByte(best) = sniff(src.as_bytes(), chunk_size) else { panic!("Single byte dialect expected") };
+            assert_eq!(best.comment_prefix, Some(b"//".to_vec()), "chunk_size {chunk_size}");
+            assert_eq!(best.record_terminator, RecordTerminator::Crlf);
+        }
+    }
+
+    /// Lines looking like comments are data, if that's valid too
+    #[test]
+    fn comment_prefix_in_data() {
+        let src = "tag,posts\nrust,10\n#go,20\nzig,30\n#c,40\nnim,50\nodin,60\n";
+        let best = dialects(src).into_iter().max().unwrap();
+        assert_eq!((best.comment_prefix, best.total_rows), (None, 7));
+    }
+
+    #[test]
+    fn blank_lines() {
+        let src = format!("id,name,age\n{}\n\n{}\n", rows(1, 3), rows(4, 6));
+        let best = dialects(&src).into_iter().max().unwrap();
+        assert_eq!(best.blank_lines, BlankLines::Skip);
+        assert_eq!((best.skip_rows, best.skip_footer_rows, best.total_rows), (0, 0, 7));
+        assert!(dialects(&format!("id,name,age\n{}", rows(1, 6))).iter().all(|d| d.blank_lines == BlankLines::Reject));
+    }
+
+    /// End of file ends the last row, with or without separator at row end
+    #[test]
+    fn unterminated_last_row() {
+        let src = format!("id,name,age\n{}", rows(1, 6));
+        let best = dialects(src.trim_end()).into_iter().max().unwrap();
+        assert_eq!(best.total_rows, 7);
+
+        let src = src.replace('\n', ";\n").replace(',', ";");
+        let best = dialects(src.trim_end()).into_iter().max().unwrap();
+        assert_eq!((best.field_separator, best.field_separator_is_terminator, best.total_rows), (b';', true, 7));
+    }
+}
@@ -0,0 +1,142 @@
+//! Random tables written in a given dialect, for round-trip tests:
+//! whatever is written by `SingleByteDialectWriter` must be sniffed
+//! and converted back into the same cells.
+
+use crate::asv::AsvReader;
+use crate::{CsvSniffer, Dialect, SingleByteDialect};
+
+/// xorshift64*, so tests need no dependencies and are reproducible
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+pub(crate) struct Table {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<Vec<u8>>>,
+}
+
+impl Table {
+    /// Header and rows as they are expected in converted output
+    pub fn cells(&self) -> Vec<Vec<Vec<u8>>> {
+        let header = self.header.iter().map(|name| name.as_bytes().to_vec()).collect();
+        std::iter::once(header).chain(self.rows.iter().cloned()).collect()
+    }
+}
+
+/// Table of `rows` rows, which can be written in `dialect`. Cells
+/// of the first row start with a letter, so no column is empty.
+pub(crate) fn random_table(rng: &mut Rng, dialect: &SingleByteDialect, rows: usize) -> Table {
+    let columns = 2 + rng.below(4);
+    let header = (0..columns).map(|i| format!("column{i}")).collect();
+
+    // quote or escape chars of other dialects would make
+    // the file ambiguous, so only its own special bytes are used
+    let mut specials = vec![dialect.field_separator, b'\n', b'\r'];
+    specials.extend(dialect.escape_char);
+    specials.extend(dialect.quote_char);
+
+    let mut table = Table { header, rows: vec![] };
+    while table.rows.len() < rows {
+        let row: Vec<Vec<u8>> = (0..columns)
+            .map(|_| random_cell(rng, &specials, table.rows.is_empty()))
+            .collect();
+
+        // values which can't be represented are just generated again
+        let mut writer = SingleByteDialect { header: None, ..dialect.clone() }.writer(vec![]);
+        if writer.write_record(&row).is_ok() {
+            table.rows.push(row);
+        }
+    }
+
+    table
+}
+
+fn random_cell(rng: &mut Rng, specials: &[u8], is_first_row: bool) -> Vec<u8> {
+    const PLAIN: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789 ";
+
+    let len = if is_first_row { 1 + rng.below(8) } else { rng.below(10) };
+    (0..len)
+        .map(|i| {
+            if (i > 0 || !is_first_row) && rng.chance(10) {
+                specials[rng.below(specials.len())]
+            } else if i == 0 && is_first_row {
+                PLAIN[rng.below(26)]
+            } else {
+                PLAIN[rng.below(PLAIN.len())]
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn write_table(dialect: &SingleByteDialect, table: &Table) -> Vec<u8> {
+    let header = SingleByteDialect { header: Some(table.header.clone()), ..dialect.clone() };
+    let mut writer = header.writer(vec![]);
+    for row in &table.rows {
+        writer.write_record(row).expect("Rows are representable");
+    }
+    writer.finish().expect("Written to memory")
+}
+
+/// Best dialect of `src`, read by chunks of `chunk_size` bytes
+pub(crate) fn sniff(src: &[u8], chunk_size: usize) -> Dialect {
+    // `process` fills whole buffers, so chunks are passed one by one
+    let mut sniffer = CsvSniffer::new(None);
+    for chunk in src.chunks(chunk_size) {
+        sniffer.process_chunk(chunk);
+    }
+    sniffer.dialects()
+        .into_iter()
+        .max()
+        .unwrap_or_else(|| panic!("No dialect found for {:?}", String::from_utf8_lossy(src)))
+}
+
+/// Rows of converted output, placeholder header is dropped
+pub(crate) fn asv_cells(asv: &[u8]) -> Vec<Vec<Vec<u8>>> {
+    let mut reader = AsvReader::new(asv);
+    let header = reader.headers().expect("Read from memory");
+    header
+        .map(|names| names.into_iter().map(String::into_bytes).collect())
+        .into_iter()
+        .chain(reader.records().map(|record| {
+            record.expect("Read from memory").iter().map(<[u8]>::to_vec).collect()
+        }))
+        .collect()
+}
+
+/// Compares rows, the first different one is shown as text
+pub(crate) fn assert_same_cells(actual: &[Vec<Vec<u8>>], expected: &[Vec<Vec<u8>>], context: impl Fn() -> String) {
+    let text = |row: Option<&Vec<Vec<u8>>>| row.map(|row| row
+        .iter()
+        .map(|cell| String::from_utf8_lossy(cell).into_owned())
+        .collect::<Vec<_>>()
+    );
+
+    if let Some(i) = (0..actual.len().max(expected.len())).find(|i| actual.get(*i) != expected.get(*i)) {
+        panic!(
+            "row {i} of {} differs, {}\n  actual: {:?}\nexpected: {:?}",
+            expected.len(),
+            context(),
+            text(actual.get(i)),
+            text(expected.get(i)),
+        );
+    }
+}
@@ -1,25 +1,134 @@
 mod detector;
+#[cfg(test)]
+mod generator;
 mod normalizer;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod reader;
+mod tokenizer;
+mod writer;
 
 use std::cmp::Ordering;
+use crate::bom::{self, Bom};
 pub use detector::*;
+pub use reader::{SingleByteDialectReader, SingleByteDialectRecords};
+pub use writer::SingleByteDialectWriter;
 
 #[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
 pub struct SingleByteDialect {
     pub header: Option<Vec<String>>,
+    /// First row is `__NO_HEADER__` placeholders (e.g. our own ASV output),
+    /// so it's neither header nor data.
+    pub has_placeholder_header: bool,
 
     pub field_separator: u8,
     pub quote_char: Option<u8>,
+    pub quoting: Quoting,
+    /// Quote char in the middle of unquoted value is a part of it
+    /// (e.g. `O'Brien`), always so with `Quoting::Rfc4180`
+    pub quote_at_field_start_only: bool,
     pub escape_char: Option<u8>,
+    pub escape_scope: EscapeScope,
     pub empty_columns: Vec<bool>,
     pub numeric_columns: Vec<bool>,
     pub record_terminator: RecordTerminator,
+    /// Rows starting with it are comments (neither header nor data),
+    /// they are not quoted or escaped
+    pub comment_prefix: Option<Vec<u8>>,
 
     pub field_separator_is_terminator: bool,
     pub has_escaped_line_breaks: bool,
     pub has_quoted_line_breaks: bool,
 
     pub total_rows: usize,
+    /// Fields opened by `quote_char` during detection
+    pub quoted_fields: usize,
+    /// Occurrences of `escape_char` during detection
+    pub escapes: usize,
+    /// Rows of different shape before header (title, blank lines, ...),
+    /// they are not converted
+    pub skip_rows: usize,
+    /// Rows of different shape at file end (totals, row count, ...),
+    /// they follow `total_rows` rows and are not converted either
+    pub skip_footer_rows: usize,
+    /// Empty lines between rows, they are not counted in `total_rows`
+    pub blank_lines: BlankLines,
+    /// Rows of different shape inside table, tolerated by `Tolerance`,
+    /// they are not counted in `total_rows` and are quarantined
+    pub malformed_rows: Vec<MalformedRow>,
+
+    /// Character encoding name (e.g. `windows-1251`), detected
+    /// only with `encoding` feature
+    pub encoding: Option<&'static str>,
+    /// File starts with byte order mark, which is not part of header
+    pub bom: Option<Bom>,
+}
+
+impl SingleByteDialect {
+    /// Rows at file start which are not data records (preamble and header)
+    pub(crate) fn header_rows(&self) -> u64 {
+        self.skip_rows as u64 + (self.header.is_some() || self.has_placeholder_header) as u64
+    }
+
+    /// Offset of the first row
+    pub(crate) fn bom_len(&self) -> u64 {
+        bom::len(self.bom) as u64
+    }
+
+    /// Number of the first footer row, preamble rows are not counted
+    pub(crate) fn footer_row(&self) -> Option<usize> {
+        (self.skip_footer_rows > 0).then_some(self.total_rows)
+    }
+}
+
+/// Meaning of `quote_char` inside values
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Quoting {
+    /// Every quote char starts or ends quoted part of value
+    #[default] Toggle,
+    /// RFC 4180: quote char starts quoted value only at field start,
+    /// doubled quote char inside quoted value is a literal quote
+    Rfc4180(StrayQuotes),
+}
+
+/// Quote chars in the middle of unquoted value (`Quoting::Rfc4180`)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum StrayQuotes {
+    /// Part of value
+    #[default] Literal,
+    /// Dialect doesn't match the file
+    Reject,
+}
+
+/// Where `escape_char` escapes next byte
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum EscapeScope {
+    #[default] Everywhere,
+    /// Escape char in unquoted value is a part of it (e.g. `C:\temp`)
+    InsideQuotes,
+}
+
+/// Row of different shape inside table, or row which can't be
+/// parsed (e.g. with unterminated quote)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct MalformedRow {
+    /// Number of well-formed rows before it (including header,
+    /// but not preamble), so consecutive malformed rows share it
+    pub row: usize,
+    /// Offset of its first byte after BOM (in decoded
+    /// input, if encoding is not UTF-8)
+    pub offset: u64,
+}
+
+/// Empty lines between rows (not in preamble, which is skipped anyway)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum BlankLines {
+    /// Dialect doesn't match the file
+    #[default] Reject,
+    /// Dropped, their number is `NormalizeReport::blank_lines`
+    Skip,
+    /// Record of empty values, never detected
+    EmptyRecord,
 }
 
 #[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
@@ -28,8 +137,26 @@ pub enum RecordTerminator {
     Byte(u8)
 }
 
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd<Self> for SingleByteDialect {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.malformed_rows.len() != other.malformed_rows.len() {
+            return Some(other.malformed_rows.len().cmp(&self.malformed_rows.len()))
+        }
+        // whole file being one table is preferred over skipping preamble
+        if self.skip_rows != other.skip_rows {
+            return Some(other.skip_rows.cmp(&self.skip_rows))
+        }
+        if self.skip_footer_rows != other.skip_footer_rows {
+            return Some(other.skip_footer_rows.cmp(&self.skip_footer_rows))
+        }
+        if self.blank_lines == BlankLines::Reject && other.blank_lines != BlankLines::Reject {
+            return Some(Ordering::Greater)
+        }
+        if self.blank_lines != BlankLines::Reject && other.blank_lines == BlankLines::Reject {
+            return Some(Ordering::Less)
+        }
+
         // header is preferred over no-header
         if self.header.is_some() && other.header.is_none() {
             return Some(Ordering::Greater)
@@ -80,6 +207,67 @@ impl PartialOrd<Self> for SingleByteDialect {
             return Some(Ordering::Less)
         }
 
+        // rows looking like comments are data, if that's valid too
+        if self.comment_prefix.is_none() && other.comment_prefix.is_some() {
+            return Some(Ordering::Greater)
+        }
+        if self.comment_prefix.is_some() && other.comment_prefix.is_none() {
+            return Some(Ordering::Less)
+        }
+
+        // quote char which actually opens quoted fields is preferred
+        // (counts differ between quoting variants, so they are not compared)
+        if self.quoted_fields > 0 && other.quoted_fields == 0 {
+            return Some(Ordering::Greater)
+        }
+        if self.quoted_fields == 0 && other.quoted_fields > 0 {
+            return Some(Ordering::Less)
+        }
+        // and no quote char over one which never opens a field, same for escape char
+        if self.quote_char.is_none() && other.quote_char.is_some() {
+            return Some(Ordering::Greater)
+        }
+        if self.quote_char.is_some() && other.quote_char.is_none() {
+            return Some(Ordering::Less)
+        }
+        if self.escapes > 0 && other.escapes == 0 {
+            return Some(Ordering::Greater)
+        }
+        if self.escapes == 0 && other.escapes > 0 {
+            return Some(Ordering::Less)
+        }
+        if self.escape_char.is_none() && other.escape_char.is_some() {
+            return Some(Ordering::Greater)
+        }
+        if self.escape_char.is_some() && other.escape_char.is_none() {
+            return Some(Ordering::Less)
+        }
+
+        // RFC 4180 quoting is preferred, as toggling drops doubled quotes
+        let is_rfc_self = matches!(self.quoting, Quoting::Rfc4180(_));
+        let is_rfc_other = matches!(other.quoting, Quoting::Rfc4180(_));
+        if is_rfc_self && !is_rfc_other {
+            return Some(Ordering::Greater)
+        }
+        if !is_rfc_self && is_rfc_other {
+            return Some(Ordering::Less)
+        }
+        if self.quote_at_field_start_only && !other.quote_at_field_start_only {
+            return Some(Ordering::Greater)
+        }
+        if !self.quote_at_field_start_only && other.quote_at_field_start_only {
+            return Some(Ordering::Less)
+        }
+
+        // escaping outside quotes, when it's valid either way,
+        // only drops backslashes from plain values
+        if self.escape_scope == EscapeScope::InsideQuotes && other.escape_scope != EscapeScope::InsideQuotes {
+            return Some(Ordering::Greater)
+        }
+        if self.escape_scope != EscapeScope::InsideQuotes && other.escape_scope == EscapeScope::InsideQuotes {
+            return Some(Ordering::Less)
+        }
+
         // pessimize too long headers (100+ unicode characters)
         let has_long_header_self = self.header
             .iter()
@@ -110,7 +298,7 @@ impl PartialOrd<Self> for SingleByteDialect {
             return Some(Ordering::Less)
         }
 
-        return Some(Ordering::Equal);
+        Some(Ordering::Equal)
     }
 }
 
@@ -1,222 +1,660 @@
-use std::io::{Read, Write};
-use crate::dialects::{Normalize, SingleByteDialect};
-use crate::dialects::single_byte::RecordTerminator;
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+use crate::asv::{self, AsvRecord};
+use crate::dialects::{BlankLines, MalformedRow, Normalize, NormalizeOptions, NormalizeReport, QuarantineSink, SingleByteDialect};
+use crate::dialects::control_bytes::ControlByteGuard;
+use crate::dialects::single_byte::tokenizer::{TokenSink, Tokenizer};
+use crate::index::IndexBuilder;
+#[cfg(feature = "parallel")]
+use crate::dialects::control_bytes::GuardPosition;
+#[cfg(feature = "parallel")]
+use crate::dialects::single_byte::tokenizer::TokenizerState;
 
 impl Normalize for SingleByteDialect {
-    fn to_asv(&self, src: impl Read, dst: impl Write) {
+    fn to_asv(&self, src: impl Read, dst: impl Write, options: &NormalizeOptions) -> Result<NormalizeReport> {
         let mut normalizer = SingleByteDialectNormalizer::new(
             src,
             dst,
-            self.clone()
+            self.clone(),
+            options,
         );
 
-        normalizer.normalize();
+        normalizer.normalize()?;
+        Ok(normalizer.into_report())
+    }
+
+    fn slice_to_asv(&self, src: &[u8], dst: impl Write, options: &NormalizeOptions) -> Result<NormalizeReport> {
+        let mut normalizer = SingleByteDialectNormalizer::new(
+            io::empty(),
+            dst,
+            self.clone(),
+            options,
+        );
+
+        normalizer.normalize_slice(src)?;
+        Ok(normalizer.into_report())
     }
 }
 struct SingleByteDialectNormalizer <W: Write, R: Read> {
-    writer: W,
     reader: R,
+    tokenizer: Tokenizer,
+    output: AsvOutput<W>,
+    /// Stream offset of the current chunk
+    offset: u64,
+}
+
+/// Writes tokens as ASV, values are written by whole spans
+struct AsvOutput<W: Write> {
+    writer: W,
     dialect: SingleByteDialect,
-    escape_active: bool,
-    quote_active: bool,
     current_column: usize,
-    prev_char_was_cr: bool,
+    last_emitted_column: Option<usize>,
+    /// Preamble rows are skipped before the first row
+    preamble_rows_left: usize,
     is_first_row: bool,
+    guard: ControlByteGuard,
+    /// Start state was guessed, so it may be wrong
+    speculative: bool,
+    index: Option<IndexBuilder>,
+    /// Kept comment lines, if requested
+    comments: Option<Vec<Vec<u8>>>,
+    comment: Vec<u8>,
+    /// Rows starting from this one are footer
+    footer_row: Option<usize>,
+    in_footer: bool,
+    /// Kept footer rows, if requested
+    footer: Option<Vec<AsvRecord>>,
+    footer_record: AsvRecord,
+    /// Skipped blank lines
+    blank_lines: usize,
+    /// Rows to quarantine, not known in speculative mode
+    malformed_rows: Vec<MalformedRow>,
+    /// Number of passed malformed rows
+    quarantined: usize,
+    /// Offset of the current row, if it's malformed
+    quarantine_start: Option<u64>,
+    /// Source ranges of malformed rows, if they are kept
+    quarantine_ranges: Option<Vec<(u64, u64)>>,
+    /// Receives malformed rows copied from source chunks, see `copy_quarantine`
+    quarantine: Option<QuarantineSink>,
+    /// Beginning of malformed row from previous chunks
+    quarantine_row: Vec<u8>,
+}
+
+/// Position dependent state of conversion,
+/// allows to convert file by independent ranges
+#[cfg(feature = "parallel")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub(super) struct NormalizerState {
+    pub tokenizer: TokenizerState,
+    pub current_column: usize,
+    pub preamble_rows_left: usize,
+    pub is_first_row: bool,
+    pub in_footer: bool,
+    pub quarantined: usize,
+    pub quarantine_start: Option<u64>,
+    pub guard: GuardPosition,
 }
 
 impl<W: Write, R: Read> SingleByteDialectNormalizer<W, R> {
-    fn new(reader: R, writer: W, dialect: SingleByteDialect) -> Self {
+    fn new(reader: R, writer: W, dialect: SingleByteDialect, options: &NormalizeOptions) -> Self {
         Self {
-            writer,
             reader,
-            dialect,
-            escape_active: false,
-            quote_active: false,
-            current_column: 0,
-            prev_char_was_cr: false,
-            is_first_row: true,
+            tokenizer: Tokenizer::new(&dialect),
+            output: AsvOutput::new(writer, dialect, options),
+            offset: 0,
         }
     }
 
-    fn normalize(&mut self) {
-        let mut buffer = vec![b'0'; 1024*1024*16]; // 16 MiB chunks
-        const PLACEHOLDER: &[u8] = b"__NO_HEADER__\x1f";
-
-        if self.dialect.header.is_none() {
-            let mut header: Vec<u8> = self.dialect.empty_columns
-                .iter()
-                .flat_map(|_| PLACEHOLDER)
-                .copied()
-                .collect();
-
-            *header.last_mut().unwrap() = b'\x1e';
-            self.writer.write_all(&header).unwrap();
+    fn into_report(self) -> NormalizeReport {
+        NormalizeReport {
+            control_byte_cells: self.output.guard.affected_cells(),
+            invalid_utf8_cells: self.output.guard.invalid_utf8_cells(),
+            record_index: self.output.index.map(IndexBuilder::finish),
+            comments: self.output.comments.unwrap_or_default(),
+            footer: self.output.footer.unwrap_or_default(),
+            blank_lines: self.output.blank_lines,
+            repaired_rows: vec![],
         }
+    }
+
+    fn normalize(&mut self) -> Result<()> {
+        let mut buffer = vec![b'0'; 1024*1024*16]; // 16 MiB chunks
 
+        write_header(&self.output.dialect, &mut self.output.writer)?;
         loop {
-            let chunk_size = self.reader.read(&mut buffer).unwrap();
+            let chunk_size = self.reader.read(&mut buffer)?;
             if chunk_size == 0 {
                 break
             }
 
-            self.process_chunk(&buffer[0..chunk_size]);
+            self.process_chunk(&buffer[0..chunk_size])?;
         }
+
+        self.finish()
     }
 
-    fn process_chunk(&mut self, chunk: &[u8]) {
-        for c in chunk {
-            // these try_* functions returns true if byte is accepted/consumed
+    fn normalize_slice(&mut self, src: &[u8]) -> Result<()> {
+        write_header(&self.output.dialect, &mut self.output.writer)?;
+        self.process_chunk(src)?;
+        self.finish()
+    }
 
-            if self.dialect.has_escaped_line_breaks && self.try_escape(c) {
-                continue
-            }
+    #[inline]
+    fn process_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        self.tokenizer
+            .feed(chunk, &mut self.output)
+            .map_err(|(e, _)| e)?;
+
+        self.output.copy_quarantine(self.offset, chunk)?;
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
 
-            if self.dialect.has_quoted_line_breaks && self.try_quote(c) {
-                continue
-            }
+    fn finish(&mut self) -> Result<()> {
+        self.tokenizer.finish(&mut self.output)?;
+        self.output.copy_quarantine(self.offset, &[])?;
+        if let Some(quarantine) = &self.output.quarantine {
+            quarantine.flush()?;
+        }
+        self.output.writer.flush()
+    }
+}
 
-            if self.try_next_row(c) {
-                continue;
-            }
+/// Writes placeholder header if file has no header.
+/// Placeholder row which is already in the source will be copied as is.
+pub(super) fn write_header(dialect: &SingleByteDialect, writer: &mut impl Write) -> Result<()> {
+    if dialect.header.is_some() || dialect.has_placeholder_header {
+        return Ok(())
+    }
 
-            if !self.dialect.has_escaped_line_breaks && self.try_escape(c) {
-                continue;
-            }
+    let mut header: Vec<u8> = dialect.empty_columns
+        .iter()
+        .filter(|is_empty| !**is_empty)
+        .flat_map(|_| [asv::NO_HEADER.as_bytes(), &[asv::FIELD_SEPARATOR]])
+        .flatten()
+        .copied()
+        .collect();
 
-            if !self.dialect.has_quoted_line_breaks && self.try_quote(c) {
-                continue;
-            }
+    if let Some(last) = header.last_mut() {
+        *last = asv::RECORD_SEPARATOR;
+    }
+    writer.write_all(&header)
+}
 
-            if self.try_next_field(c) {
-                continue;
-            }
+/// Converts part of the file at `offset` starting with `state` (without
+/// header), returns state at the end of `range`. If `speculative`, fails
+/// on first row of wrong length, as `state` is wrong for sure then.
+/// The last range of the file is `is_last`, its end ends the last row.
+///
+/// If record index is requested, starts of all rows in range are
+/// returned, as record numbers are not known yet. Source ranges of
+/// malformed rows are returned, if they are kept.
+#[cfg(feature = "parallel")]
+#[allow(clippy::type_complexity)]
+pub(super) fn normalize_range(
+    dialect: &SingleByteDialect,
+    options: &NormalizeOptions,
+    (offset, range): (u64, &[u8]),
+    state: NormalizerState,
+    speculative: bool,
+    is_last: bool,
+    dst: &mut Vec<u8>,
+) -> Result<(NormalizerState, NormalizeReport, Option<Vec<u64>>, Vec<(u64, u64)>)> {
+    let mut tokenizer = Tokenizer::new(dialect);
+    let mut output = AsvOutput::new(dst, dialect.clone(), options);
+    tokenizer.set_state(state.tokenizer);
+    tokenizer.set_offset(offset);
+    output.set_state(state);
+    output.speculative = speculative;
+    // row numbers are not known
+    if speculative {
+        output.footer_row = None;
+        output.malformed_rows.clear();
+    }
+    output.index = output.index.map(|_| IndexBuilder::row_starts());
 
-            self.try_next_char(c);
-        }
+    tokenizer
+        .feed(range, &mut output)
+        .map_err(|(e, _)| e)?;
+    if is_last {
+        tokenizer.finish(&mut output)?;
     }
 
-    #[inline]
-    fn try_escape(&mut self, c: &u8) -> bool {
-        if self.escape_active {
-            self.escape_active = false;
-            self.writer.write_all(&[*c]).unwrap();
-            return true
-        }
-
-        if let Some(e) = self.dialect.escape_char {
-            if *c == e {
-                self.escape_active = true;
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+    let report = NormalizeReport {
+        control_byte_cells: output.guard.affected_cells(),
+        record_index: None,
+        invalid_utf8_cells: output.guard.invalid_utf8_cells(),
+        comments: output.comments.take().unwrap_or_default(),
+        footer: output.footer.take().unwrap_or_default(),
+        blank_lines: output.blank_lines,
+        repaired_rows: vec![],
+    };
+    let state = output.state(&tokenizer);
+    let quarantine_ranges = output.quarantine_ranges.take().unwrap_or_default();
+    Ok((state, report, output.index.map(IndexBuilder::into_row_starts), quarantine_ranges))
+}
+
+#[cfg(feature = "parallel")]
+impl NormalizerState {
+    /// State at the beginning of the file, which has `skip_rows` preamble rows
+    pub fn start(skip_rows: usize) -> Self {
+        Self {
+            tokenizer: TokenizerState::default(),
+            current_column: 0,
+            preamble_rows_left: skip_rows,
+            is_first_row: true,
+            in_footer: false,
+            quarantined: 0,
+            quarantine_start: None,
+            guard: GuardPosition::default(),
         }
     }
 
-    #[inline]
-    fn try_quote(&mut self, c: &u8) -> bool {
-        if let Some(q) = self.dialect.quote_char {
-            let was_active = self.quote_active;
-            let should_switch = q == *c;
-
-            if should_switch {
-                self.quote_active = !self.quote_active;
-                return true
-            } else if was_active {
-                self.writer.write_all(&[*c]).unwrap();
-                return true
-            }
+    /// State at the beginning of well-formed row (but not the first one)
+    /// number `row`, after `quarantined` malformed rows
+    pub fn row_start(row: usize, quarantined: usize) -> Self {
+        Self {
+            is_first_row: false,
+            quarantined,
+            guard: GuardPosition {
+                row,
+                cell_affected: false,
+            },
+            ..Self::start(0)
         }
-        false
     }
+}
 
-    #[inline]
-    fn try_next_row(&mut self, c: &u8) -> bool {
-        let is_break = match &self.dialect.record_terminator {
-            RecordTerminator::Byte(t) => {
-                c == t
-            }
-            RecordTerminator::Crlf => {
-                if *c == b'\r' {
-                    self.prev_char_was_cr = true;
-                    return true; // consume CR byte, but no line break here yet
-                } else if *c == b'\n' && self.prev_char_was_cr {
-                    self.prev_char_was_cr = false;
-                    true
-                } else {
-                    self.prev_char_was_cr = false;
-                    false
-                }
-            }
-        };
+impl<W: Write> AsvOutput<W> {
+    fn new(writer: W, mut dialect: SingleByteDialect, options: &NormalizeOptions) -> Self {
+        let malformed_rows = std::mem::take(&mut dialect.malformed_rows);
+        let last_emitted_column = dialect.empty_columns
+            .iter()
+            .rposition(|is_empty| !is_empty);
+        let index = options.record_index
+            .map(|every| IndexBuilder::new(every, dialect.header_rows(), dialect.bom_len()));
+        let has_header = dialect.header.is_some() || dialect.has_placeholder_header;
 
-        if is_break {
-            self.end_row();
+        Self {
+            writer,
+            preamble_rows_left: dialect.skip_rows,
+            footer_row: dialect.footer_row(),
+            dialect,
+            current_column: 0,
+            last_emitted_column,
+            is_first_row: true,
+            guard: ControlByteGuard::new(options.control_bytes).with_utf8(options.invalid_utf8, has_header),
+            speculative: false,
+            index,
+            comments: options.keep_comments.then(Vec::new),
+            comment: vec![],
+            in_footer: false,
+            footer: options.keep_footer.then(Vec::new),
+            footer_record: AsvRecord::default(),
+            blank_lines: 0,
+            malformed_rows,
+            quarantined: 0,
+            quarantine_start: None,
+            quarantine_ranges: options.quarantine.is_some().then(Vec::new),
+            quarantine: options.quarantine.clone(),
+            quarantine_row: vec![],
         }
+    }
 
-        is_break
+    #[cfg(feature = "parallel")]
+    fn state(&self, tokenizer: &Tokenizer) -> NormalizerState {
+        NormalizerState {
+            tokenizer: tokenizer.state(),
+            current_column: self.current_column,
+            preamble_rows_left: self.preamble_rows_left,
+            is_first_row: self.is_first_row,
+            in_footer: self.in_footer,
+            quarantined: self.quarantined,
+            quarantine_start: self.quarantine_start,
+            guard: self.guard.position(),
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn set_state(&mut self, state: NormalizerState) {
+        self.current_column = state.current_column;
+        self.preamble_rows_left = state.preamble_rows_left;
+        self.is_first_row = state.is_first_row;
+        self.in_footer = state.in_footer;
+        self.quarantined = state.quarantined;
+        self.quarantine_start = state.quarantine_start;
+        self.guard.set_position(state.guard);
     }
 
     #[inline]
-    fn try_next_field(&mut self, c: &u8) -> bool {
-        if *c == self.dialect.field_separator {
-            self.end_field();
-            true
+    fn should_emit_current_column(&self) -> bool {
+        if let Some(c) = self.dialect.empty_columns.get(self.current_column) {
+            !c
         } else {
             false
         }
     }
 
+    #[cold]
+    fn skip_preamble_row(&mut self, next_row_offset: u64) -> Result<()> {
+        self.preamble_rows_left -= 1;
+        self.current_column = 0;
+        if let Some(index) = &mut self.index {
+            index.end_row(next_row_offset);
+        }
+        Ok(())
+    }
+
+    /// Number of fields in row, including empty ones
+    fn row_length(&self) -> usize {
+        self.dialect.empty_columns.len() + self.dialect.field_separator_is_terminator as usize
+    }
+
+    /// Values of footer are kept, malformed rows are copied from source
+    #[cold]
+    fn push_skipped(&mut self, span: &[u8]) -> Result<()> {
+        if self.in_footer && self.footer.is_some() {
+            self.footer_record.push_bytes(span);
+        }
+        Ok(())
+    }
+
+    /// Finds out if the next row (starting at `next_row_offset`,
+    /// unless there are blank or comment lines) is malformed or footer
     #[inline]
-    fn try_next_char(&mut self, c: &u8) -> bool {
-        if self.is_first_row && !self.should_emit_current_column() {
-            return true
+    fn classify_next_row(&mut self, next_row_offset: u64) {
+        let is_malformed = self.malformed_rows
+            .get(self.quarantined)
+            .is_some_and(|malformed| malformed.row == self.guard.row());
+
+        self.quarantine_start = is_malformed.then_some(next_row_offset);
+        self.in_footer = !is_malformed && Some(self.guard.row()) == self.footer_row;
+    }
+
+    /// Malformed row starts after blank or comment line
+    #[cold]
+    fn move_quarantine_start(&mut self, next_row_offset: u64) {
+        self.quarantine_start = Some(next_row_offset);
+        self.quarantine_row.clear();
+    }
+
+    #[cold]
+    fn end_quarantined_row(&mut self, start: u64, next_row_offset: u64) -> Result<()> {
+        if let Some(ranges) = &mut self.quarantine_ranges {
+            ranges.push((start, next_row_offset));
         }
+        self.quarantined += 1;
+        self.current_column = 0;
+        self.classify_next_row(next_row_offset);
+        Ok(())
+    }
 
-        self.writer.write_all(&[*c]).unwrap();
-        true
+    /// Writes source bytes of malformed rows found in `chunk`, which
+    /// is at `offset` of the stream. Row may continue in the next chunk,
+    /// then its beginning is kept until the row ends (its start moves
+    /// past blank and comment lines).
+    fn copy_quarantine(&mut self, offset: u64, chunk: &[u8]) -> Result<()> {
+        let (Some(ranges), Some(quarantine)) = (&mut self.quarantine_ranges, &self.quarantine) else {
+            return Ok(())
+        };
+
+        let chunk_pos = |row_offset: u64| (row_offset.max(offset) - offset) as usize;
+        for (start, end) in ranges.drain(..) {
+            quarantine.write_all(&std::mem::take(&mut self.quarantine_row))?;
+            quarantine.write_all(&chunk[chunk_pos(start)..chunk_pos(end)])?;
+        }
+        if let Some(start) = self.quarantine_start {
+            self.quarantine_row.extend_from_slice(&chunk[chunk_pos(start)..]);
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn end_footer_row(&mut self) -> Result<()> {
+        self.footer_record.end_record(self.dialect.field_separator_is_terminator);
+        let record = std::mem::take(&mut self.footer_record);
+        if let Some(footer) = &mut self.footer {
+            footer.push(record);
+        }
+        Ok(())
     }
 
     #[inline]
-    fn end_field(&mut self) {
-        self.quote_active = false;
-        self.escape_active = false;
+    fn end_field(&mut self) -> Result<()> {
+        if (self.is_first_row && self.preamble_rows_left > 0) || self.quarantine_start.is_some() {
+            return Ok(())
+        }
+        if self.in_footer {
+            self.footer_record.end_field();
+            return Ok(())
+        }
+        self.guard.end_cell();
 
         // skip empty columns
         let should_emit= self.should_emit_current_column();
+        let is_last = Some(self.current_column) == self.last_emitted_column;
         self.current_column += 1;
 
         // no emit delimiter after last column
-        if self.current_column == self.dialect.empty_columns.len() {
-            return
+        if should_emit && !is_last {
+            self.guard.separator(&mut self.writer)?;
         }
 
-        if should_emit {
-            self.writer.write_all(b"\x1f").unwrap();
+        Ok(())
+    }
+}
+
+impl<W: Write> TokenSink for AsvOutput<W> {
+    type Error = std::io::Error;
+
+    #[inline]
+    fn data(&mut self, span: &[u8]) -> Result<()> {
+        if self.is_first_row && (self.preamble_rows_left > 0 || !self.should_emit_current_column()) {
+            return Ok(())
+        }
+        if self.in_footer || self.quarantine_start.is_some() {
+            return self.push_skipped(span)
         }
+
+        self.guard.write(&mut self.writer, span)
     }
 
     #[inline]
-    fn should_emit_current_column(&self) -> bool {
-        if let Some(c) = self.dialect.empty_columns.get(self.current_column) {
-            !c
-        } else {
-            false
+    fn literal(&mut self, span: &[u8]) -> Result<()> {
+        if self.is_first_row && (self.preamble_rows_left > 0 || !self.should_emit_current_column()) {
+            return Ok(())
         }
+        if self.in_footer || self.quarantine_start.is_some() {
+            return self.push_skipped(span)
+        }
+
+        self.guard.write(&mut self.writer, span)
     }
 
     #[inline]
-    fn end_row(&mut self) {
-        self.end_field();
-        debug_assert_eq!(self.current_column, self.dialect.empty_columns.len());
+    fn open_quote(&mut self, _quote: u8) -> Result<()> {
+        Ok(())
+    }
 
+    #[inline]
+    fn escape(&mut self, _escape: u8) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn separator(&mut self, _separator: u8) -> Result<()> {
+        self.end_field()
+    }
+
+    #[inline]
+    fn end_row(&mut self, next_row_offset: u64) -> Result<()> {
+        if self.is_first_row && self.preamble_rows_left > 0 {
+            return self.skip_preamble_row(next_row_offset)
+        }
+        if let Some(start) = self.quarantine_start {
+            return self.end_quarantined_row(start, next_row_offset)
+        }
+        if self.in_footer {
+            return self.end_footer_row()
+        }
+
+        self.end_field()?;
+        let row_length = self.row_length();
+        if self.speculative && self.current_column != row_length {
+            return Err(Error::new(ErrorKind::InvalidData, "Inconsistent row length, wrong range start state"))
+        }
+        debug_assert_eq!(self.current_column, row_length);
 
-        self.prev_char_was_cr = false;
         self.current_column = 0;
         self.is_first_row = false;
-        self.writer.write_all(b"\x1e").unwrap();
+        self.guard.end_row(&mut self.writer)?;
+        self.classify_next_row(next_row_offset);
+        if let Some(index) = &mut self.index {
+            index.end_row(next_row_offset);
+        }
+        Ok(())
+    }
+
+    /// Blank lines in preamble are rows of it
+    #[cold]
+    fn blank_line(&mut self, next_row_offset: u64) -> Result<()> {
+        if self.is_first_row && self.preamble_rows_left > 0 {
+            return self.end_row(next_row_offset)
+        }
+        if self.quarantine_start.is_some() {
+            self.move_quarantine_start(next_row_offset);
+        }
+
+        match self.dialect.blank_lines {
+            BlankLines::Reject => Err(Error::new(ErrorKind::InvalidData, "Blank line")),
+            BlankLines::Skip => {
+                self.blank_lines += 1;
+                Ok(())
+            }
+            BlankLines::EmptyRecord => {
+                for _ in 1..self.row_length() {
+                    self.end_field()?;
+                }
+                self.end_row(next_row_offset)
+            }
+        }
+    }
+
+    /// Malformed rows of the dialect are skipped by tokenizer
+    #[cold]
+    fn malformed(&mut self, reason: &'static str) -> Result<()> {
+        if self.quarantine_start.is_some() {
+            return Ok(())
+        }
+        Err(Error::new(ErrorKind::InvalidData, reason))
+    }
+
+    #[inline]
+    fn comment(&mut self, span: &[u8]) -> Result<()> {
+        if self.comments.is_some() {
+            self.comment.extend_from_slice(span);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn end_comment(&mut self, next_row_offset: u64) -> Result<()> {
+        if let Some(comments) = &mut self.comments {
+            comments.push(std::mem::take(&mut self.comment));
+        }
+        if self.quarantine_start.is_some() {
+            self.move_quarantine_start(next_row_offset);
+        }
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::dialects::{BlankLines, Normalize, NormalizeOptions, Quoting, RecordTerminator, SingleByteDialect, Utf8Policy};
+
+    fn dialect() -> SingleByteDialect {
+        SingleByteDialect {
+            header: Some(vec!["a".into(), "b".into()]),
+            field_separator: b',',
+            quote_char: Some(b'"'),
+            empty_columns: vec![false, false],
+            record_terminator: RecordTerminator::Byte(b'\n'),
+            ..Default::default()
+        }
+    }
+
+    fn convert(dialect: &SingleByteDialect, src: &[u8], options: &NormalizeOptions) -> (Vec<u8>, Vec<usize>) {
+        let mut asv = vec![];
+        let report = dialect.to_asv(src, &mut asv, options).unwrap();
+        (asv, report.invalid_utf8_cells)
+    }
+
+    #[test]
+    fn header_of_empty_column_is_dropped() {
+        let dialect = SingleByteDialect {
+            header: Some(vec!["a\"b".into(), "c".into()]),
+            field_separator: b',',
+            quote_char: Some(b'"'),
+            quoting: Quoting::Rfc4180(Default::default()),
+            empty_columns: vec![true, false],
+            record_terminator: RecordTerminator::Byte(b'\n'),
+            ..Default::default()
+        };
+
+        let mut asv = vec![];
+        dialect.to_asv(&b"\"a\"\"b\",c\n,1\n,2\n"[..], &mut asv, &NormalizeOptions::default()).unwrap();
+        assert_eq!(asv, b"c\x1e1\x1e2\x1e");
+    }
+
+    /// Separator bytes passed through in values don't end cells
+    #[test]
+    fn invalid_utf8_in_cells() {
+        let src = b"a,b\n\"x\x1fy\",\xff\n\"\xfe\",2\n";
+        let options = |invalid_utf8| NormalizeOptions { invalid_utf8, ..Default::default() };
+
+        assert_eq!(
+            convert(&dialect(), src, &options(Utf8Policy::DropCell)),
+            (b"a\x1fb\x1ex\x1fy\x1f\x1e\x1f2\x1e".to_vec(), vec![1, 1]),
+        );
+        assert_eq!(
+            convert(&dialect(), src, &options(Utf8Policy::DropRow)),
+            (b"a\x1fb\x1e".to_vec(), vec![1, 1]),
+        );
+        assert_eq!(
+            convert(&dialect(), b"a,b\nC:\\x,\xff\n", &options(Utf8Policy::HexEscape)),
+            (b"a\x1fb\x1eC:\\x5Cx\x1f\\xFF\x1e".to_vec(), vec![0, 1]),
+        );
+    }
+
+    /// Comment lines are neither quoted nor escaped
+    #[test]
+    fn comment_lines() {
+        let dialect = SingleByteDialect { comment_prefix: Some(b"#".to_vec()), ..dialect() };
+        let options = NormalizeOptions { keep_comments: true, ..Default::default() };
+        let mut asv = vec![];
+        let report = dialect.to_asv(&b"# \"a\na,b\n1,2\n#,x\n3,4\n"[..], &mut asv, &options).unwrap();
+        assert_eq!(asv, b"a\x1fb\x1e1\x1f2\x1e3\x1f4\x1e");
+        assert_eq!(report.comments, [b"# \"a".to_vec(), b"#,x".to_vec()]);
+    }
+
+    /// Line break at start of quoted line is not a blank line
+    #[test]
+    fn blank_lines() {
+        let dialect = SingleByteDialect { blank_lines: BlankLines::Skip, has_quoted_line_breaks: true, ..dialect() };
+        let mut asv = vec![];
+        let report = dialect.to_asv(&b"a,b\n1,2\n\n\n3,\"\n\"\n\n"[..], &mut asv, &NormalizeOptions::default()).unwrap();
+        assert_eq!(asv, b"a\x1fb\x1e1\x1f2\x1e3\x1f\n\x1e");
+        assert_eq!(report.blank_lines, 3);
+    }
+
+    /// End of file ends the last row, separator at its end included
+    #[test]
+    fn unterminated_last_row() {
+        let options = NormalizeOptions::default();
+        assert_eq!(convert(&dialect(), b"a,b\n1,2\n3,\"4\"", &options).0, b"a\x1fb\x1e1\x1f2\x1e3\x1f4\x1e");
+
+        let dialect = SingleByteDialect { field_separator_is_terminator: true, ..dialect() };
+        assert_eq!(convert(&dialect, b"a,b,\n1,2,\n3,4,", &options).0, b"a\x1fb\x1e1\x1f2\x1e3\x1f4\x1e");
+    }
+}
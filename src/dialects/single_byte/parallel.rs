@@ -0,0 +1,226 @@
+//! Parallel conversion of file which is fully in memory.
+//!
+//! File is split into ranges, each range starts right after record
+//! terminator and is converted assuming that it's the beginning of
+//! a row. Then ranges are checked in order: if previous range
+//! actually ends in the middle of a row (e.g. terminator was quoted),
+//! range is converted again starting from the real state.
+
+use std::cmp::min;
+use std::io::{Error, Result, Write};
+use std::ops::RangeInclusive;
+use memchr::{memchr, memmem};
+use rayon::prelude::*;
+use crate::dialects::{NormalizeOptions, NormalizeReport};
+use crate::index::IndexBuilder;
+use super::{RecordTerminator, SingleByteDialect};
+use super::normalizer::{normalize_range, write_header, NormalizerState};
+
+#[cfg(not(test))]
+const RANGE_SIZE: usize = 1024 * 1024 * 16; // 16 MiB
+
+// tests use tiny ranges, so that rows and quoted values cross them
+#[cfg(test)]
+thread_local! {
+    static RANGE_SIZE: std::cell::Cell<usize> = const { std::cell::Cell::new(1024 * 1024 * 16) };
+}
+
+fn range_size() -> usize {
+    #[cfg(test)]
+    { RANGE_SIZE.get() }
+    #[cfg(not(test))]
+    { RANGE_SIZE }
+}
+
+type RangeResult = Result<(Vec<u8>, NormalizerState, NormalizeReport, Option<Vec<u64>>, Vec<(u64, u64)>)>;
+
+impl SingleByteDialect {
+    pub(crate) fn to_asv_parallel(
+        &self,
+        src: &[u8],
+        mut dst: impl Write,
+        options: &NormalizeOptions,
+        threads: usize,
+    ) -> Result<NormalizeReport> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(Error::other)?;
+
+        write_header(self, &mut dst)?;
+
+        // only a few ranges per thread are kept in memory at once
+        let ranges = self.split_ranges(src);
+        let batch_size = pool.current_num_threads() * 2;
+
+        let mut state = NormalizerState::start(self.skip_rows);
+        let mut report = NormalizeReport::default();
+        let mut index = options.record_index
+            .map(|every| IndexBuilder::new(every, self.header_rows(), self.bom_len()));
+        for batch in ranges.chunks(batch_size) {
+            // state of first range in batch is already known
+            let results: Vec<RangeResult> = pool.install(|| batch
+                .par_iter()
+                .enumerate()
+                .map(|(i, range)| if i == 0 {
+                    self.normalize_range(*range, state, false, src.len(), options)
+                } else {
+                    self.normalize_range(*range, NormalizerState::row_start(0, 0), true, src.len(), options)
+                })
+                .collect()
+            );
+
+            for (i, (range, result)) in batch.iter().zip(results).enumerate() {
+                let is_guessed = i != 0;
+                let is_row_start = state == NormalizerState::row_start(state.guard.row, state.quarantined);
+
+                let (output, end, range_report, row_starts, quarantine_ranges) = match result {
+                    // ranges reaching footer or malformed rows are converted
+                    // again, as both are found by row number
+                    Ok((output, mut end, range_report, row_starts, quarantine_ranges))
+                        if is_guessed && is_row_start && self.is_well_formed(state.guard.row..=state.guard.row + end.guard.row) =>
+                    {
+                        // row numbers were counted from range start
+                        end.guard.row += state.guard.row;
+                        end.quarantined += state.quarantined;
+                        (output, end, range_report, row_starts, quarantine_ranges)
+                    }
+                    result if !is_guessed => result?,
+                    // wrong guess or error with wrong row number
+                    _ => self.normalize_range(*range, state, false, src.len(), options)?,
+                };
+
+                dst.write_all(&output)?;
+                report.control_byte_cells += range_report.control_byte_cells;
+                report.comments.extend(range_report.comments);
+                report.footer.extend(range_report.footer);
+                report.blank_lines += range_report.blank_lines;
+                if let Some(quarantine) = &options.quarantine {
+                    for (start, end) in quarantine_ranges {
+                        quarantine.write_all(&src[start as usize..end as usize])?;
+                    }
+                }
+                if let (Some(index), Some(row_starts)) = (&mut index, row_starts) {
+                    row_starts.into_iter().for_each(|offset| index.end_row(offset));
+                }
+                state = end;
+            }
+        }
+
+        if let Some(quarantine) = &options.quarantine {
+            quarantine.flush()?;
+        }
+        dst.flush()?;
+        report.record_index = index.map(IndexBuilder::finish);
+        Ok(report)
+    }
+
+    fn normalize_range(
+        &self,
+        range: (u64, &[u8]),
+        state: NormalizerState,
+        speculative: bool,
+        src_len: usize,
+        options: &NormalizeOptions,
+    ) -> RangeResult {
+        let is_last = range.0 as usize + range.1.len() == src_len;
+        let mut output = Vec::with_capacity(range.1.len());
+        let (end, report, row_starts, quarantine_ranges) = normalize_range(self, options, range, state, speculative, is_last, &mut output)?;
+        Ok((output, end, report, row_starts, quarantine_ranges))
+    }
+
+    /// Rows with numbers in `rows` are neither footer nor malformed
+    /// (malformed rows after them are numbered the same)
+    fn is_well_formed(&self, rows: RangeInclusive<usize>) -> bool {
+        let malformed = self.malformed_rows.partition_point(|malformed| malformed.row < *rows.start());
+        let is_before_footer = self.footer_row().map_or(true, |footer_row| *rows.end() < footer_row);
+        is_before_footer && self.malformed_rows.get(malformed).map_or(true, |malformed| malformed.row > *rows.end())
+    }
+
+    /// Ranges of about `range_size()` bytes with their offsets,
+    /// each one ends right after line break
+    fn split_ranges<'a>(&self, src: &'a [u8]) -> Vec<(u64, &'a [u8])> {
+        let mut ranges = vec![];
+        let mut start = 0;
+        while start < src.len() {
+            let mut end = min(start + range_size(), src.len());
+            end += self.find_line_break_end(&src[end..]).unwrap_or(src.len() - end);
+
+            ranges.push((start as u64, &src[start..end]));
+            start = end;
+        }
+
+        ranges
+    }
+
+    fn find_line_break_end(&self, haystack: &[u8]) -> Option<usize> {
+        match self.record_terminator {
+            RecordTerminator::Crlf => memmem::find(haystack, b"\r\n").map(|pos| pos + 2),
+            RecordTerminator::Byte(t) => memchr(t, haystack).map(|pos| pos + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::{CsvSniffer, Dialect, NormalizeOptions, QuarantineSink, Tolerance};
+    use crate::dialects::Normalize;
+    use super::RANGE_SIZE;
+
+    /// Table with everything which depends on conversion state:
+    /// preamble, comments, quoted line breaks, malformed rows and footer
+    fn source() -> Vec<u8> {
+        let mut src = b"Monthly report\nGenerated on 2026-10-18\n\nid,name,note\n".to_vec();
+        for i in 0..200 {
+            let row = match i % 37 {
+                5 => format!("# comment {i}\n"),
+                11 => format!("{i},name{i},\"two\nlines\"\n"),
+                23 if i < 100 => format!("{i},name{i},extra,value\n"),
+                _ => format!("{i},name{i},note {i}\n"),
+            };
+            src.extend_from_slice(row.as_bytes());
+        }
+        src.extend_from_slice(b"Total 200\n(200 rows)\n");
+        src
+    }
+
+    #[test]
+    fn same_output_as_sequential() {
+        let src = source();
+        let mut sniffer = CsvSniffer::new(None).with_tolerance(Tolerance::Records(5));
+        sniffer.process(&mut &src[..]);
+        let Some(Dialect::SingleByte(dialect)) = sniffer.dialects().into_iter().max() else {
+            panic!("Single-byte dialect is expected")
+        };
+        assert_eq!(
+            (dialect.skip_rows, dialect.skip_footer_rows, dialect.malformed_rows.len(), dialect.has_quoted_line_breaks),
+            (3, 2, 3, true),
+        );
+        assert!(dialect.comment_prefix.is_some());
+
+        let options = |quarantine: &Arc<Mutex<Vec<u8>>>| NormalizeOptions {
+            record_index: Some(7),
+            keep_comments: true,
+            keep_footer: true,
+            quarantine: Some(QuarantineSink(quarantine.clone())),
+            ..Default::default()
+        };
+        let expected_quarantine = Arc::new(Mutex::new(vec![]));
+        let mut expected = vec![];
+        let expected_report = dialect.to_asv(&src[..], &mut expected, &options(&expected_quarantine)).unwrap();
+        assert_eq!(expected_quarantine.lock().unwrap().split(|c| *c == b'\n').count(), 4);
+
+        for range_size in [1, 7, 16, 50, 333, src.len()] {
+            RANGE_SIZE.set(range_size);
+            for threads in [1, 4] {
+                let quarantine = Arc::new(Mutex::new(vec![]));
+                let mut actual = vec![];
+                let report = dialect.to_asv_parallel(&src, &mut actual, &options(&quarantine), threads).unwrap();
+                assert_eq!(actual, expected, "range size {range_size}, {threads} threads");
+                assert_eq!(report, expected_report, "range size {range_size}, {threads} threads");
+                assert_eq!(*quarantine.lock().unwrap(), *expected_quarantine.lock().unwrap(), "range size {range_size}, {threads} threads");
+            }
+        }
+    }
+}
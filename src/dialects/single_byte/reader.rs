@@ -0,0 +1,246 @@
+//! Parses records of the source file in given dialect, either from
+//! the start or from any record found with `RecordIndex`.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use crate::asv::AsvRecord;
+use crate::bom;
+use crate::dialects::{BlankLines, MalformedRow, SingleByteDialect};
+use crate::dialects::single_byte::tokenizer::{TokenSink, Tokenizer};
+use crate::index::RecordIndex;
+
+const BUFFER_SIZE: usize = 64 * 1024; // 64 KiB
+
+pub struct SingleByteDialectReader<R: Read> {
+    reader: R,
+    tokenizer: Tokenizer,
+    collector: RecordCollector,
+    buffer: Vec<u8>,
+    is_eof: bool,
+}
+
+/// Builds records from tokens, skipping first `preamble` rows (blank
+/// lines are rows there), then `skip` rows and all rows after
+/// `records_left` records (footer). Malformed rows are dropped, `row`
+/// is the number of well-formed rows before the current one.
+#[derive(Default)]
+struct RecordCollector {
+    preamble: u64,
+    skip: u64,
+    malformed_rows: Vec<MalformedRow>,
+    row: usize,
+    quarantined: usize,
+    records_left: Option<u64>,
+    field_separator_is_terminator: bool,
+    blank_lines: BlankLines,
+    /// Number of fields in row, including empty ones
+    row_length: usize,
+    current: AsvRecord,
+    ready: VecDeque<AsvRecord>,
+}
+
+impl SingleByteDialect {
+    /// Reads data records from the start of the file (BOM and header are skipped)
+    pub fn reader<R: Read>(&self, mut src: R) -> Result<SingleByteDialectReader<R>> {
+        bom::skip(&mut src, self.bom)?;
+        let preamble = self.skip_rows as u64;
+        Ok(SingleByteDialectReader::new(src, self, preamble, self.header_rows() - preamble, 0))
+    }
+
+    /// Reads data records starting from `record` (0 is the first data
+    /// record), only the part of the file after the nearest indexed
+    /// record is parsed. `index` must be built for this file and dialect.
+    pub fn reader_at<R: Read + Seek>(
+        &self,
+        mut src: R,
+        index: &RecordIndex,
+        record: u64,
+    ) -> Result<SingleByteDialectReader<R>> {
+        let Some((offset, skip)) = index.locate(record) else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Record is out of index range"))
+        };
+
+        src.seek(SeekFrom::Start(offset))?;
+        Ok(SingleByteDialectReader::new(src, self, 0, skip, record))
+    }
+
+    /// Number of data records, footer rows follow them
+    fn data_records(&self) -> u64 {
+        self.total_rows as u64 + self.skip_rows as u64 - self.header_rows()
+    }
+}
+
+impl<R: Read> SingleByteDialectReader<R> {
+    fn new(reader: R, dialect: &SingleByteDialect, preamble: u64, skip: u64, first_record: u64) -> Self {
+        let records_left = (dialect.skip_footer_rows > 0)
+            .then(|| dialect.data_records().saturating_sub(first_record));
+        // number of the first parsed well-formed row, header is row 0
+        let row = (first_record + dialect.header_rows() - dialect.skip_rows as u64 - skip) as usize;
+        let quarantined = dialect.malformed_rows.partition_point(|malformed| malformed.row < row);
+
+        Self {
+            reader,
+            tokenizer: Tokenizer::new(dialect),
+            collector: RecordCollector {
+                preamble,
+                skip,
+                malformed_rows: dialect.malformed_rows.clone(),
+                row,
+                quarantined,
+                records_left,
+                field_separator_is_terminator: dialect.field_separator_is_terminator,
+                blank_lines: dialect.blank_lines,
+                row_length: dialect.empty_columns.len() + dialect.field_separator_is_terminator as usize,
+                ..Default::default()
+            },
+            buffer: vec![0; BUFFER_SIZE],
+            is_eof: false,
+        }
+    }
+
+    /// Reads next record into `record` with unquoted and unescaped
+    /// values. Returns `false` when there are no records left.
+    pub fn read_record(&mut self, record: &mut AsvRecord) -> Result<bool> {
+        loop {
+            if let Some(mut next) = self.collector.ready.pop_front() {
+                std::mem::swap(record, &mut next);
+                return Ok(true)
+            }
+            if self.is_eof {
+                return Ok(false)
+            }
+
+            let size = self.reader.read(&mut self.buffer)?;
+            if size == 0 {
+                self.is_eof = true;
+                self.tokenizer.finish(&mut self.collector)?;
+                continue
+            }
+
+            self.tokenizer
+                .feed(&self.buffer[..size], &mut self.collector)
+                .map_err(|(e, _)| e)?;
+        }
+    }
+
+    pub fn records(&mut self) -> SingleByteDialectRecords<'_, R> {
+        SingleByteDialectRecords { reader: self }
+    }
+}
+
+pub struct SingleByteDialectRecords<'a, R: Read> {
+    reader: &'a mut SingleByteDialectReader<R>,
+}
+
+impl<R: Read> Iterator for SingleByteDialectRecords<'_, R> {
+    type Item = Result<AsvRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = AsvRecord::default();
+        match self.reader.read_record(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl TokenSink for RecordCollector {
+    type Error = Error;
+
+    #[inline]
+    fn data(&mut self, span: &[u8]) -> Result<()> {
+        if self.preamble == 0 && self.skip == 0 && self.records_left != Some(0) {
+            self.current.push_bytes(span);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn literal(&mut self, span: &[u8]) -> Result<()> {
+        self.data(span)
+    }
+
+    #[inline]
+    fn open_quote(&mut self, _quote: u8) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn escape(&mut self, _escape: u8) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn separator(&mut self, _separator: u8) -> Result<()> {
+        if self.preamble == 0 && self.skip == 0 && self.records_left != Some(0) {
+            self.current.end_field();
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn end_row(&mut self, _next_row_offset: u64) -> Result<()> {
+        if self.preamble > 0 {
+            self.preamble -= 1;
+            return Ok(())
+        }
+        if self.malformed_rows.get(self.quarantined).is_some_and(|malformed| malformed.row == self.row) {
+            self.quarantined += 1;
+            self.current = AsvRecord::default();
+            return Ok(())
+        }
+        self.row += 1;
+        if self.skip > 0 {
+            self.skip -= 1;
+            return Ok(())
+        }
+        match &mut self.records_left {
+            Some(0) => return Ok(()),
+            Some(records_left) => *records_left -= 1,
+            None => {}
+        }
+
+        self.current.end_record(self.field_separator_is_terminator);
+        self.ready.push_back(std::mem::take(&mut self.current));
+        Ok(())
+    }
+
+    #[cold]
+    fn blank_line(&mut self, next_row_offset: u64) -> Result<()> {
+        if self.preamble > 0 || self.records_left == Some(0) {
+            return self.end_row(next_row_offset)
+        }
+
+        match self.blank_lines {
+            BlankLines::Reject => Err(Error::new(ErrorKind::InvalidData, "Blank line")),
+            BlankLines::Skip => Ok(()),
+            BlankLines::EmptyRecord => {
+                for _ in 1..self.row_length {
+                    self.separator(0)?;
+                }
+                self.end_row(next_row_offset)
+            }
+        }
+    }
+
+    /// Malformed rows of the dialect are skipped by tokenizer
+    #[cold]
+    fn malformed(&mut self, reason: &'static str) -> Result<()> {
+        if self.preamble == 0 && self.malformed_rows.get(self.quarantined).is_some_and(|malformed| malformed.row == self.row) {
+            return Ok(())
+        }
+        Err(Error::new(ErrorKind::InvalidData, reason))
+    }
+
+    /// Comment lines are not records
+    #[inline]
+    fn comment(&mut self, _span: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn end_comment(&mut self, _next_row_offset: u64) -> Result<()> {
+        Ok(())
+    }
+}
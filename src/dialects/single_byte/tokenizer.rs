@@ -0,0 +1,636 @@
+//! Splits `SingleByteDialect` stream into values, fields and rows.
+//!
+//! Runs of plain bytes are found with `memchr` and passed as
+//! whole spans, only structural bytes (separator, quote, escape,
+//! record terminator) are processed one by one.
+//!
+//! First bytes of every row are processed one by one too, if there
+//! is comment prefix, comment lines are passed as whole spans.
+
+use memchr::memchr;
+use crate::dialects::byte_set::ByteSet;
+use super::{EscapeScope, Quoting, RecordTerminator, SingleByteDialect, StrayQuotes};
+
+pub(crate) trait TokenSink {
+    type Error;
+
+    /// Unquoted and unescaped bytes of value
+    fn data(&mut self, span: &[u8]) -> Result<(), Self::Error>;
+    /// Quoted or escaped bytes of value
+    fn literal(&mut self, span: &[u8]) -> Result<(), Self::Error>;
+    /// Quote which starts quoted part of value
+    fn open_quote(&mut self, quote: u8) -> Result<(), Self::Error>;
+    /// Escape char, next byte is literal
+    fn escape(&mut self, escape: u8) -> Result<(), Self::Error>;
+    /// Unquoted and unescaped field separator
+    fn separator(&mut self, separator: u8) -> Result<(), Self::Error>;
+    /// Record terminator, next row starts at `next_row_offset` byte of the stream
+    fn end_row(&mut self, next_row_offset: u64) -> Result<(), Self::Error>;
+    /// Record terminator right at row start, it's not a row of one empty value
+    fn blank_line(&mut self, next_row_offset: u64) -> Result<(), Self::Error>;
+    /// Input doesn't match the dialect, e.g. rejected stray quote.
+    /// Unless it fails, the rest of row is skipped and the row ends.
+    fn malformed(&mut self, reason: &'static str) -> Result<(), Self::Error>;
+    /// Bytes of comment line, including prefix
+    fn comment(&mut self, span: &[u8]) -> Result<(), Self::Error>;
+    /// Terminator of comment line, it's not a row
+    fn end_comment(&mut self, next_row_offset: u64) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub(crate) struct TokenizerState {
+    pub quote_active: bool,
+    pub escape_active: bool,
+    pub prev_char_was_cr: bool,
+    /// Current field has any bytes already
+    pub field_started: bool,
+    /// Quote char inside quoted value, which is either
+    /// closing or the first one of doubled quote
+    pub quote_pending: bool,
+    /// Current row has any bytes already
+    pub row_started: bool,
+    /// Bytes of comment prefix found at row start
+    pub comment_matched: usize,
+    pub in_comment: bool,
+    /// Row is malformed, its bytes are skipped up to record terminator
+    pub skip_row: bool,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub(crate) struct Tokenizer {
+    field_separators: ByteSet,
+    quote_char: Option<u8>,
+    quoting: Quoting,
+    quote_at_field_start_only: bool,
+    escape_char: Option<u8>,
+    /// `escape_char` if it's special outside quotes
+    unquoted_escape_char: Option<u8>,
+    record_terminator: RecordTerminator,
+    has_escaped_line_breaks: bool,
+    has_quoted_line_breaks: bool,
+    comment_prefix: Option<Vec<u8>>,
+
+    unquoted: ByteSet,
+    quoted: ByteSet,
+
+    state: TokenizerState,
+    /// Stream offset of the current chunk
+    chunk_offset: u64,
+    /// Stream offset of the byte processed by `process_byte`
+    offset: u64,
+    /// Stream offset of the current row, if it's known
+    row_offset: Option<u64>,
+}
+
+impl Tokenizer {
+    pub fn new(dialect: &SingleByteDialect) -> Self {
+        Self::with_separators(dialect, &[dialect.field_separator])
+    }
+
+    /// Tokenizer reporting any of `separators`, `dialect.field_separator` is ignored
+    pub fn with_separators(dialect: &SingleByteDialect, separators: &[u8]) -> Self {
+        let mut tokenizer = Self {
+            field_separators: ByteSet::new(separators),
+            quote_char: dialect.quote_char,
+            quoting: dialect.quoting,
+            quote_at_field_start_only: dialect.quote_at_field_start_only || dialect.quoting != Quoting::Toggle,
+            escape_char: dialect.escape_char,
+            unquoted_escape_char: dialect.escape_char.filter(|_| dialect.escape_scope == EscapeScope::Everywhere),
+            record_terminator: dialect.record_terminator.clone(),
+            has_escaped_line_breaks: dialect.has_escaped_line_breaks,
+            has_quoted_line_breaks: dialect.has_quoted_line_breaks,
+            comment_prefix: dialect.comment_prefix.clone().filter(|prefix| !prefix.is_empty()),
+            row_offset: Some(0),
+            ..Default::default()
+        };
+
+        let line_break = tokenizer.line_break();
+        let mut quoted = vec![];
+        if !tokenizer.has_quoted_line_breaks {
+            quoted.push(line_break);
+        }
+        quoted.extend([tokenizer.quote_char, tokenizer.escape_char].into_iter().flatten());
+
+        tokenizer.quoted = ByteSet::new(&quoted);
+        tokenizer.set_separators(separators);
+        tokenizer
+    }
+
+    /// Replaces reported separators, tokenizer state is kept
+    pub fn set_separators(&mut self, separators: &[u8]) {
+        let mut unquoted = separators.to_vec();
+        unquoted.push(self.line_break());
+        unquoted.extend([self.quote_char, self.unquoted_escape_char].into_iter().flatten());
+
+        self.field_separators = ByteSet::new(separators);
+        self.unquoted = ByteSet::new(&unquoted);
+    }
+
+    /// Replaces comment prefix, tokenizer state is kept
+    pub fn set_comment_prefix(&mut self, prefix: Option<Vec<u8>>) {
+        self.comment_prefix = prefix.filter(|prefix| !prefix.is_empty());
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn state(&self) -> TokenizerState {
+        self.state
+    }
+
+    /// Continue tokenizing from the middle of the file
+    #[cfg(feature = "parallel")]
+    pub fn set_state(&mut self, state: TokenizerState) {
+        self.state = state;
+    }
+
+    /// Offset of the next fed byte in the whole stream, state must be set already
+    #[cfg(feature = "parallel")]
+    pub fn set_offset(&mut self, offset: u64) {
+        self.chunk_offset = offset;
+        self.row_offset = (!self.state.row_started).then_some(offset);
+    }
+
+    /// On error returns also position of failed byte in chunk
+    #[inline]
+    pub fn feed<S: TokenSink>(&mut self, chunk: &[u8], sink: &mut S) -> Result<(), (S::Error, usize)> {
+        let mut pos = 0;
+        while pos < chunk.len() {
+            let rest = &chunk[pos..];
+
+            // escaped byte, byte after CR and byte after quote depend on previous one
+            let span = if self.state.escape_active || self.state.prev_char_was_cr || self.state.quote_pending {
+                0
+            } else if self.state.in_comment || self.state.skip_row {
+                memchr(self.line_break(), rest).unwrap_or(rest.len())
+            } else if !self.state.row_started && self.comment_prefix.is_some() {
+                0
+            } else if self.state.quote_active {
+                self.quoted.find(rest).unwrap_or(rest.len())
+            } else {
+                self.unquoted.find(rest).unwrap_or(rest.len())
+            };
+
+            let res = if span > 0 {
+                if self.state.skip_row {
+                    Ok(())
+                } else if self.state.in_comment {
+                    sink.comment(&rest[..span])
+                } else if self.state.quote_active {
+                    sink.literal(&rest[..span])
+                } else {
+                    self.state.row_started = true;
+                    self.state.field_started = true;
+                    sink.data(&rest[..span])
+                }
+            } else {
+                self.offset = self.chunk_offset + pos as u64;
+                self.process_byte(rest[0], sink)
+            };
+            res.map_err(|e| (e, pos))?;
+
+            pos += span.max(1);
+        }
+
+        self.chunk_offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// End of stream ends the last row, if it has no record terminator.
+    /// Unterminated quote or escape is malformed input.
+    pub fn finish<S: TokenSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        self.offset = self.chunk_offset;
+        if self.state.in_comment {
+            if self.state.prev_char_was_cr {
+                sink.comment(b"\r")?;
+            }
+            self.state = TokenizerState::default();
+            return sink.end_comment(self.chunk_offset)
+        }
+
+        // bytes matched as comment prefix are the start of row
+        let matched = std::mem::take(&mut self.state.comment_matched);
+        if matched > 0 {
+            self.state.row_started = true;
+            let prefix = self.comment_prefix.clone().unwrap_or_default();
+            for b in &prefix[..matched] {
+                self.process_byte(*b, sink)?;
+            }
+        }
+
+        if self.state.quote_active {
+            sink.malformed("Unterminated quote at end of file")?;
+        } else if std::mem::take(&mut self.state.prev_char_was_cr) && !self.state.skip_row {
+            // CR without LF at end of file is a part of the last value
+            sink.data(b"\r")?;
+        } else if self.state.escape_active {
+            sink.malformed("Dangling escape at end of file")?;
+        }
+
+        if !self.state.row_started {
+            return Ok(())
+        }
+        self.state = TokenizerState::default();
+        sink.end_row(self.chunk_offset)
+    }
+
+    #[inline]
+    fn process_byte<S: TokenSink>(&mut self, c: u8, sink: &mut S) -> Result<(), S::Error> {
+        if self.state.in_comment {
+            return self.process_comment_byte(c, sink)
+        }
+        if self.state.skip_row {
+            return self.skip_row_byte(c, sink)
+        }
+        if !self.state.row_started && self.comment_prefix.is_some() {
+            return self.start_row(c, sink)
+        }
+        self.state.row_started = true;
+
+        // CR without LF is a part of value, also when escape char follows it
+        if self.state.prev_char_was_cr && c != b'\n' {
+            self.state.prev_char_was_cr = false;
+            if self.state.quote_active {
+                sink.literal(b"\r")?;
+            } else {
+                self.state.field_started = true;
+                sink.data(b"\r")?;
+            }
+        }
+
+        let field_started = self.state.field_started;
+
+        if self.state.quote_pending {
+            self.state.quote_pending = false;
+            if Some(c) == self.quote_char {
+                self.state.quote_active = true;
+                return sink.literal(&[c])
+            }
+        }
+
+        if self.state.escape_active {
+            self.state.escape_active = false;
+            if !self.has_escaped_line_breaks && self.try_next_row(c, sink)? {
+                return Ok(())
+            }
+            return sink.literal(&[c])
+        }
+
+        let escape_char = if self.state.quote_active { self.escape_char } else { self.unquoted_escape_char };
+        if Some(c) == escape_char {
+            self.state.field_started = true;
+            self.state.escape_active = true;
+            return sink.escape(c)
+        }
+
+        if self.state.quote_active {
+            if std::mem::take(&mut self.state.prev_char_was_cr) {
+                self.malformed("Unterminated quote at end of row", sink)?;
+                return self.end_row(sink)
+            }
+
+            if Some(c) == self.quote_char {
+                self.state.quote_active = false;
+                self.state.quote_pending = self.quoting != Quoting::Toggle;
+                return Ok(())
+            }
+
+            if !self.has_quoted_line_breaks && c == self.line_break() {
+                if self.record_terminator == RecordTerminator::Crlf {
+                    self.state.prev_char_was_cr = true;
+                    return Ok(())
+                }
+                self.malformed("Unterminated quote at end of row", sink)?;
+                return self.end_row(sink)
+            }
+            return sink.literal(&[c])
+        }
+
+        if Some(c) == self.quote_char {
+            self.state.prev_char_was_cr = false;
+            self.state.field_started = true;
+            if field_started && self.quote_at_field_start_only {
+                return self.stray_quote(c, sink)
+            }
+
+            self.state.quote_active = true;
+            return sink.open_quote(c)
+        }
+
+        if self.try_next_row(c, sink)? {
+            return Ok(())
+        }
+
+        if self.field_separators.contains(c) {
+            self.state.field_started = false;
+            return sink.separator(c)
+        }
+
+        self.state.field_started = true;
+        sink.data(&[c])
+    }
+
+    /// Matches first bytes of row against comment prefix
+    fn start_row<S: TokenSink>(&mut self, c: u8, sink: &mut S) -> Result<(), S::Error> {
+        let prefix = self.comment_prefix.as_deref().unwrap_or_default();
+        let matched = self.state.comment_matched;
+        if prefix.get(matched) == Some(&c) {
+            self.state.comment_matched += 1;
+            if self.state.comment_matched == prefix.len() {
+                self.state.comment_matched = 0;
+                self.state.in_comment = true;
+                return sink.comment(prefix)
+            }
+            return Ok(())
+        }
+
+        // not a comment, matched bytes are the start of row
+        self.state.comment_matched = 0;
+        self.state.row_started = true;
+        for i in 0..matched {
+            let b = self.comment_prefix.as_ref().unwrap()[i];
+            self.process_byte(b, sink)?;
+        }
+        self.process_byte(c, sink)
+    }
+
+    /// Comment line ends with the first record terminator
+    fn process_comment_byte<S: TokenSink>(&mut self, c: u8, sink: &mut S) -> Result<(), S::Error> {
+        match self.record_terminator {
+            RecordTerminator::Byte(t) if c == t => return self.end_comment(sink),
+            RecordTerminator::Byte(_) => {}
+            RecordTerminator::Crlf => {
+                if self.state.prev_char_was_cr {
+                    self.state.prev_char_was_cr = false;
+                    if c == b'\n' {
+                        return self.end_comment(sink)
+                    }
+                    sink.comment(b"\r")?;
+                }
+                if c == b'\r' {
+                    self.state.prev_char_was_cr = true;
+                    return Ok(())
+                }
+            }
+        }
+
+        sink.comment(&[c])
+    }
+
+    fn end_comment<S: TokenSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        self.state = TokenizerState::default();
+        self.row_offset = Some(self.offset + 1);
+        sink.end_comment(self.offset + 1)
+    }
+
+    /// Sink may tolerate malformed row, then the rest of it is skipped
+    #[cold]
+    fn malformed<S: TokenSink>(&mut self, reason: &'static str, sink: &mut S) -> Result<(), S::Error> {
+        sink.malformed(reason)?;
+        self.state = TokenizerState {
+            row_started: true,
+            skip_row: true,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    /// Bytes of malformed row are skipped, quoting is not taken into account
+    #[cold]
+    fn skip_row_byte<S: TokenSink>(&mut self, c: u8, sink: &mut S) -> Result<(), S::Error> {
+        match self.record_terminator {
+            RecordTerminator::Byte(t) if c == t => self.end_row(sink),
+            RecordTerminator::Byte(_) => Ok(()),
+            RecordTerminator::Crlf => {
+                if std::mem::replace(&mut self.state.prev_char_was_cr, c == b'\r') && c == b'\n' {
+                    return self.end_row(sink)
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Quote char in the middle of unquoted value
+    #[cold]
+    fn stray_quote<S: TokenSink>(&mut self, c: u8, sink: &mut S) -> Result<(), S::Error> {
+        match self.quoting {
+            Quoting::Rfc4180(StrayQuotes::Reject) => self.malformed("Stray quote in unquoted value", sink),
+            _ => sink.data(&[c]),
+        }
+    }
+
+    /// Returns true if byte is consumed as (part of) record terminator
+    #[inline]
+    fn try_next_row<S: TokenSink>(&mut self, c: u8, sink: &mut S) -> Result<bool, S::Error> {
+        match self.record_terminator {
+            RecordTerminator::Byte(t) => {
+                if c == t {
+                    self.end_row(sink)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            RecordTerminator::Crlf => {
+                if c == b'\r' {
+                    self.state.prev_char_was_cr = true;
+                    Ok(true)
+                } else if c == b'\n' && self.state.prev_char_was_cr {
+                    self.end_row(sink)?;
+                    Ok(true)
+                } else {
+                    self.state.prev_char_was_cr = false;
+                    Ok(false)
+                }
+            }
+        }
+    }
+
+    /// LF is structural only right after CR, which is always processed
+    /// byte by byte, so it's not included into spans search
+    fn line_break(&self) -> u8 {
+        match self.record_terminator {
+            RecordTerminator::Crlf => b'\r',
+            RecordTerminator::Byte(t) => t,
+        }
+    }
+
+    #[inline]
+    fn end_row<S: TokenSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        // offset of terminator's last byte
+        let blank_line_end = self.row_offset
+            .map(|start| start + (self.record_terminator == RecordTerminator::Crlf) as u64);
+
+        self.state = TokenizerState::default();
+        self.row_offset = Some(self.offset + 1);
+        if blank_line_end == Some(self.offset) {
+            return sink.blank_line(self.offset + 1)
+        }
+        sink.end_row(self.offset + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenSink, Tokenizer};
+    use crate::{Quoting, RecordTerminator, SingleByteDialect, StrayQuotes};
+
+    /// Rows of values, malformed input ends the list with its reason
+    #[derive(Default)]
+    struct Rows {
+        rows: Vec<Vec<String>>,
+        row: Vec<String>,
+        value: String,
+        opened_quotes: usize,
+    }
+
+    impl Rows {
+        fn push(&mut self, span: &[u8]) -> Result<(), &'static str> {
+            self.value.push_str(std::str::from_utf8(span).unwrap());
+            Ok(())
+        }
+    }
+
+    impl TokenSink for Rows {
+        type Error = &'static str;
+
+        fn data(&mut self, span: &[u8]) -> Result<(), Self::Error> {
+            self.push(span)
+        }
+
+        fn literal(&mut self, span: &[u8]) -> Result<(), Self::Error> {
+            self.push(span)
+        }
+
+        fn open_quote(&mut self, _quote: u8) -> Result<(), Self::Error> {
+            self.opened_quotes += 1;
+            Ok(())
+        }
+
+        fn escape(&mut self, _escape: u8) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn separator(&mut self, _separator: u8) -> Result<(), Self::Error> {
+            self.row.push(std::mem::take(&mut self.value));
+            Ok(())
+        }
+
+        fn end_row(&mut self, _next_row_offset: u64) -> Result<(), Self::Error> {
+            self.separator(0)?;
+            self.rows.push(std::mem::take(&mut self.row));
+            Ok(())
+        }
+
+        fn blank_line(&mut self, _next_row_offset: u64) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn malformed(&mut self, reason: &'static str) -> Result<(), Self::Error> {
+            Err(reason)
+        }
+
+        fn comment(&mut self, _span: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn end_comment(&mut self, _next_row_offset: u64) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn tokenize(dialect: &SingleByteDialect, src: &[u8]) -> Result<Rows, &'static str> {
+        let mut rows = Rows::default();
+        let mut tokenizer = Tokenizer::new(dialect);
+        tokenizer.feed(src, &mut rows).map_err(|(reason, _)| reason)?;
+        tokenizer.finish(&mut rows)?;
+        Ok(rows)
+    }
+
+    fn dialect(quoting: Quoting) -> SingleByteDialect {
+        SingleByteDialect {
+            field_separator: b',',
+            quote_char: Some(b'"'),
+            quoting,
+            quote_at_field_start_only: quoting != Quoting::Toggle,
+            record_terminator: RecordTerminator::Crlf,
+            ..Default::default()
+        }
+    }
+
+    fn values(rows: &Rows) -> Vec<Vec<&str>> {
+        rows.rows.iter().map(|row| row.iter().map(String::as_str).collect()).collect()
+    }
+
+    #[test]
+    fn doubled_quotes() {
+        let rfc = dialect(Quoting::Rfc4180(StrayQuotes::Literal));
+        let rows = tokenize(&rfc, b"a,\"\",\"x\"\"y\"\r\n\"\"\"\",b\r\n").unwrap();
+        assert_eq!(values(&rows), [vec!["a", "", "x\"y"], vec!["\"", "b"]]);
+        assert_eq!(rows.opened_quotes, 3);
+
+        let toggle = dialect(Quoting::Toggle);
+        let rows = tokenize(&toggle, b"a,\"\",\"x\"\"y\"\r\n").unwrap();
+        assert_eq!(values(&rows), [vec!["a", "", "xy"]]);
+    }
+
+    #[test]
+    fn stray_quotes() {
+        let literal = dialect(Quoting::Rfc4180(StrayQuotes::Literal));
+        let rows = tokenize(&literal, b"5'11\",x\"y\"\r\n").unwrap();
+        assert_eq!(values(&rows), [vec!["5'11\"", "x\"y\""]]);
+        assert_eq!(rows.opened_quotes, 0);
+
+        let reject = dialect(Quoting::Rfc4180(StrayQuotes::Reject));
+        assert_eq!(tokenize(&reject, b"a,b\"c\r\n").err(), Some("Stray quote in unquoted value"));
+        assert_eq!(tokenize(&reject, b"a,bc\"\r\n").err(), Some("Stray quote in unquoted value"));
+        assert!(tokenize(&reject, b"a,\"b\"\"c\"\r\n").is_ok());
+    }
+
+    /// CR without LF is a part of value, also at end of file
+    #[test]
+    fn bare_cr() {
+        let literal = dialect(Quoting::Rfc4180(StrayQuotes::Literal));
+        let rows = tokenize(&literal, b"a\rb,c\r\r\n\r,\"d\r\"\r\ne,f\r").unwrap();
+        assert_eq!(values(&rows), [vec!["a\rb", "c\r"], vec!["\r", "d\r"], vec!["e", "f\r"]]);
+
+        // quote after CR is in the middle of value
+        let rows = tokenize(&literal, b"a,\r\"b\"\r\n").unwrap();
+        assert_eq!(values(&rows), [vec!["a", "\r\"b\""]]);
+        let reject = dialect(Quoting::Rfc4180(StrayQuotes::Reject));
+        assert_eq!(tokenize(&reject, b"a,\r\"b\"\r\n").err(), Some("Stray quote in unquoted value"));
+    }
+
+    #[test]
+    fn bare_cr_before_escape_in_quotes() {
+        let escaped = SingleByteDialect {
+            escape_char: Some(b'\\'),
+            ..dialect(Quoting::Rfc4180(StrayQuotes::Literal))
+        };
+        let rows = tokenize(&escaped, b"\"a\r\\\"b\"\r\n").unwrap();
+        assert_eq!(values(&rows), [vec!["a\r\"b"]]);
+    }
+
+    #[test]
+    fn quote_after_row_end_opens_value() {
+        let reject = dialect(Quoting::Rfc4180(StrayQuotes::Reject));
+        let rows = tokenize(&reject, b"a,\r\n\"b\",\"c\"\r\n\"d\"").unwrap();
+        assert_eq!(values(&rows), [vec!["a", ""], vec!["b", "c"], vec!["d"]]);
+        assert_eq!(rows.opened_quotes, 3);
+    }
+
+    /// End of file ends the last row, bytes matched as comment prefix
+    /// are its value
+    #[test]
+    fn unterminated_last_row() {
+        let literal = dialect(Quoting::Rfc4180(StrayQuotes::Literal));
+        let rows = tokenize(&literal, b"a,b\r\nc,").unwrap();
+        assert_eq!(values(&rows), [vec!["a", "b"], vec!["c", ""]]);
+
+        let commented = SingleByteDialect { comment_prefix: Some(b"//".to_vec()), ..literal.clone() };
+        let rows = tokenize(&commented, b"// x\r\na,b\r\n/").unwrap();
+        assert_eq!(values(&rows), [vec!["a", "b"], vec!["/"]]);
+
+        assert_eq!(tokenize(&literal, b"a,\"b").err(), Some("Unterminated quote at end of file"));
+        let escaped = SingleByteDialect { escape_char: Some(b'\\'), ..literal };
+        assert_eq!(tokenize(&escaped, b"a,b\\").err(), Some("Dangling escape at end of file"));
+    }
+}
@@ -0,0 +1,424 @@
+//! Inverse of the normalizer: writes records in the given dialect,
+//! so `SingleByteDialectValidator` for that exact dialect accepts
+//! the output and normalizer parses it back into the same records.
+
+use std::io::{Error, ErrorKind, Result, Write};
+use crate::asv;
+use crate::dialects::SingleByteDialect;
+use crate::dialects::single_byte::{EscapeScope, Quoting, RecordTerminator};
+
+pub struct SingleByteDialectWriter<W: Write> {
+    writer: W,
+    dialect: SingleByteDialect,
+    columns: Option<usize>,
+    header_written: bool,
+    buffer: Vec<u8>,
+}
+
+impl SingleByteDialect {
+    pub fn writer<W: Write>(&self, dest: W) -> SingleByteDialectWriter<W> {
+        SingleByteDialectWriter::new(dest, self.clone())
+    }
+}
+
+impl<W: Write> SingleByteDialectWriter<W> {
+    pub fn new(writer: W, dialect: SingleByteDialect) -> Self {
+        let columns = if let Some(header) = &dialect.header {
+            Some(header.len())
+        } else if !dialect.empty_columns.is_empty() {
+            Some(dialect.empty_columns.len())
+        } else {
+            None // taken from the first record
+        };
+
+        Self {
+            writer,
+            dialect,
+            columns,
+            header_written: false,
+            buffer: vec![],
+        }
+    }
+
+    /// Writes single record. Header (if dialect has one) is written
+    /// before the first record.
+    pub fn write_record<I, F>(&mut self, record: I) -> Result<()>
+    where
+        I: IntoIterator<Item = F>,
+        F: AsRef<[u8]>,
+    {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+
+        let mut columns = 0;
+        for field in record {
+            if columns != 0 {
+                buffer.push(self.dialect.field_separator);
+            }
+            self.encode_field(field.as_ref(), columns == 0, &mut buffer)?;
+            columns += 1;
+        }
+
+        self.check_columns(columns)?;
+        self.end_record(&mut buffer);
+        self.write_header()?;
+        self.writer.write_all(&buffer)?;
+
+        self.buffer = buffer;
+        Ok(())
+    }
+
+    /// Writes header (if nothing was written yet) and flushes underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_header()?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if self.header_written {
+            return Ok(())
+        }
+
+        let header = match (&self.dialect.header, self.columns) {
+            (Some(header), _) => header.clone(),
+            (None, Some(columns)) if self.dialect.has_placeholder_header => {
+                vec![asv::NO_HEADER.to_string(); columns]
+            }
+            // placeholder width is unknown until first record
+            (None, None) if self.dialect.has_placeholder_header => return Ok(()),
+            (None, _) => vec![],
+        };
+        self.header_written = true;
+
+        if !header.is_empty() {
+            let mut buffer = vec![];
+            for (i, name) in header.iter().enumerate() {
+                if i != 0 {
+                    buffer.push(self.dialect.field_separator);
+                }
+                self.encode_field(name.as_bytes(), i == 0, &mut buffer)?;
+            }
+
+            self.check_columns(header.len())?;
+            self.end_record(&mut buffer);
+            self.writer.write_all(&buffer)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_columns(&mut self, columns: usize) -> Result<()> {
+        let expected = *self.columns.get_or_insert(columns);
+        if columns != expected {
+            return Err(Error::new(ErrorKind::InvalidInput, "Inconsistent record length"))
+        }
+
+        // validator rejects single-column rows, unless there
+        // is trailing separator which makes it two columns
+        let min_columns = if self.dialect.field_separator_is_terminator { 1 } else { 2 };
+        if columns < min_columns {
+            return Err(Error::new(ErrorKind::InvalidInput, "Only one column in record"))
+        }
+
+        Ok(())
+    }
+
+    fn end_record(&self, buffer: &mut Vec<u8>) {
+        if self.dialect.field_separator_is_terminator {
+            buffer.push(self.dialect.field_separator);
+        }
+
+        match self.dialect.record_terminator {
+            RecordTerminator::Crlf => buffer.extend_from_slice(b"\r\n"),
+            RecordTerminator::Byte(t) => buffer.push(t),
+        }
+    }
+
+    /// Escape every special byte if it's possible, otherwise put whole
+    /// field into quotes and escape only what can't be quoted.
+    /// First value of row starting with comment prefix is protected the same way.
+    /// With `quote_at_field_start_only` quote char which can't be protected
+    /// is written as is in the middle of unquoted value.
+    fn encode_field(&self, field: &[u8], is_row_start: bool, buffer: &mut Vec<u8>) -> Result<()> {
+        let can_escape_unquoted = self.dialect.escape_scope == EscapeScope::Everywhere;
+        let mut use_quotes = false;
+        let mut escape_first = false;
+        let mut has_literal_quotes = false;
+
+        let is_comment_like = is_row_start && self.dialect.comment_prefix
+            .as_ref()
+            .is_some_and(|prefix| !prefix.is_empty() && field.starts_with(prefix));
+        if is_comment_like {
+            if self.dialect.quote_char.is_some() {
+                use_quotes = true;
+            } else if self.dialect.escape_char.is_some() && can_escape_unquoted {
+                escape_first = self.classify(field[0]) == ByteClass::Plain;
+            } else {
+                return Err(Error::new(ErrorKind::InvalidInput, "Value looks like comment line in this dialect"))
+            }
+        }
+
+        for (i, c) in field.iter().enumerate() {
+            match self.classify(*c) {
+                ByteClass::Plain => {}
+                // literal outside quotes
+                ByteClass::Escape if !can_escape_unquoted => {}
+                ByteClass::Quote if self.is_literal_quote(i) => has_literal_quotes = true,
+                class => {
+                    if !(can_escape_unquoted && self.can_escape(class)) {
+                        let can_protect = self.can_quote(class) || self.can_escape(class);
+                        if self.dialect.quote_char.is_none() || !can_protect {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                "Value can't be represented in this dialect"
+                            ))
+                        }
+                        use_quotes = true;
+                    }
+                }
+            }
+        }
+
+        if use_quotes && has_literal_quotes {
+            return Err(Error::new(ErrorKind::InvalidInput, "Value can't be represented in this dialect"))
+        }
+
+        if use_quotes {
+            buffer.push(self.dialect.quote_char.unwrap());
+        }
+
+        if escape_first {
+            buffer.push(self.dialect.escape_char.unwrap());
+        }
+
+        for (i, c) in field.iter().enumerate() {
+            let class = self.classify(*c);
+            let protected_by_quotes = use_quotes && self.can_quote(class);
+            let is_escaped = use_quotes || can_escape_unquoted;
+            let is_literal = class == ByteClass::Quote && self.is_literal_quote(i);
+            if class != ByteClass::Plain && !protected_by_quotes && is_escaped && !is_literal {
+                buffer.push(self.dialect.escape_char.unwrap());
+            }
+            // RFC 4180 quote inside quotes is doubled
+            if class == ByteClass::Quote && protected_by_quotes {
+                buffer.push(*c);
+            }
+            buffer.push(*c);
+        }
+
+        if use_quotes {
+            buffer.push(self.dialect.quote_char.unwrap());
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn classify(&self, c: u8) -> ByteClass {
+        if Some(c) == self.dialect.escape_char {
+            return ByteClass::Escape
+        }
+        if Some(c) == self.dialect.quote_char {
+            return ByteClass::Quote
+        }
+
+        let is_line_break = match self.dialect.record_terminator {
+            // lone LF is fine, only CR starts record terminator
+            RecordTerminator::Crlf => c == b'\r',
+            RecordTerminator::Byte(t) => c == t,
+        };
+        if is_line_break {
+            return ByteClass::LineBreak
+        }
+
+        if c == self.dialect.field_separator {
+            return ByteClass::Separator
+        }
+
+        ByteClass::Plain
+    }
+
+    /// Quote char at `position` of value, which is not protected
+    /// otherwise, but isn't special there either
+    #[inline]
+    fn is_literal_quote(&self, position: usize) -> bool {
+        let can_escape_unquoted = self.dialect.escape_scope == EscapeScope::Everywhere
+            && self.can_escape(ByteClass::Quote);
+        position > 0
+            && self.dialect.quote_at_field_start_only
+            && !self.can_quote(ByteClass::Quote)
+            && !can_escape_unquoted
+    }
+
+    #[inline]
+    fn can_escape(&self, class: ByteClass) -> bool {
+        self.dialect.escape_char.is_some()
+            && (class != ByteClass::LineBreak || self.dialect.has_escaped_line_breaks)
+    }
+
+    #[inline]
+    fn can_quote(&self, class: ByteClass) -> bool {
+        self.dialect.quote_char.is_some()
+            && match class {
+                ByteClass::Plain | ByteClass::Separator => true,
+                ByteClass::LineBreak => self.dialect.has_quoted_line_breaks,
+                ByteClass::Quote => self.dialect.quoting != Quoting::Toggle,
+                ByteClass::Escape => false,
+            }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ByteClass {
+    Plain,
+    Separator,
+    LineBreak,
+    Quote,
+    Escape,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asv;
+    use crate::dialects::{Dialect, EscapeScope, Quoting, RecordTerminator, SingleByteDialect, StrayQuotes};
+    use super::super::generator::{asv_cells, assert_same_cells, random_table, sniff, write_table, Rng};
+
+    fn dialect(field_separator: u8) -> SingleByteDialect {
+        SingleByteDialect {
+            field_separator,
+            record_terminator: RecordTerminator::Byte(b'\n'),
+            ..Default::default()
+        }
+    }
+
+    /// Random tables written in `dialect` are sniffed as the same dialect
+    /// and converted back, odd seeds are sniffed by chunks of a few bytes
+    fn assert_round_trip(dialect: &SingleByteDialect) {
+        for seed in 0..20 {
+            let mut rng = Rng::new(seed);
+            let table = random_table(&mut rng, dialect, 30);
+            let src = write_table(dialect, &table);
+
+            let chunk_size = if seed % 2 == 0 { src.len() } else { seed as usize };
+            let sniffed = sniff(&src, chunk_size);
+            let Dialect::SingleByte(found) = &sniffed else {
+                panic!("Single-byte dialect is expected, seed {seed}, sniffed {sniffed:?}")
+            };
+            assert_eq!(
+                (found.field_separator, found.quote_char, found.escape_char, &found.record_terminator),
+                (dialect.field_separator, dialect.quote_char, dialect.escape_char, &dialect.record_terminator),
+                "seed {seed}, source {:?}", String::from_utf8_lossy(&src),
+            );
+            let mut asv = vec![];
+            sniffed.to_asv(&src[..], &mut asv).unwrap();
+            assert_same_cells(&asv_cells(&asv), &table.cells(), || {
+                format!("seed {seed}, sniffed {sniffed:?}, source {:?}", String::from_utf8_lossy(&src))
+            });
+        }
+    }
+
+    #[test]
+    fn field_separators() {
+        for field_separator in [b',', b';', b'\t', b'|'] {
+            assert_round_trip(&dialect(field_separator));
+        }
+    }
+
+    #[test]
+    fn quote_chars() {
+        for quote_char in [b'"', b'\''] {
+            assert_round_trip(&SingleByteDialect { quote_char: Some(quote_char), ..dialect(b',') });
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        for quote_char in [b'"', b'\''] {
+            assert_round_trip(&SingleByteDialect {
+                quote_char: Some(quote_char),
+                quoting: Quoting::Rfc4180(StrayQuotes::Literal),
+                quote_at_field_start_only: true,
+                ..dialect(b',')
+            });
+        }
+    }
+
+    /// Values may have quote chars in the middle (`O'Brien`)
+    #[test]
+    fn quote_at_field_start_only() {
+        let mut writer = SingleByteDialect {
+            quote_char: Some(b'\''),
+            quote_at_field_start_only: true,
+            ..dialect(b',')
+        }.writer(vec![]);
+        writer.write_record(["O'Brien", "5'11"]).unwrap();
+        assert_eq!(writer.finish().unwrap(), b"O'Brien,5'11\n");
+
+        for quote_char in [b'"', b'\''] {
+            assert_round_trip(&SingleByteDialect {
+                quote_char: Some(quote_char),
+                quote_at_field_start_only: true,
+                ..dialect(b';')
+            });
+        }
+    }
+
+    /// Quote char which is never used as one doesn't win the tie-breaks
+    #[test]
+    fn quote_char_of_quoted_fields() {
+        let src = b"id,name\n1,\"a\"\n2,\"c \"\"d\"\"\"\n3,e\n4,f\n5,g\n";
+        let Dialect::SingleByte(sniffed) = sniff(src, src.len()) else { panic!("Single byte dialect expected") };
+        assert_eq!(sniffed.quote_char, Some(b'"'));
+        assert_eq!(sniffed.quoting, Quoting::Rfc4180(StrayQuotes::Literal));
+    }
+
+    #[test]
+    fn escape_char() {
+        assert_round_trip(&SingleByteDialect { escape_char: Some(b'\\'), ..dialect(b',') });
+        // values are escaped rather than quoted, except for line breaks
+        assert_round_trip(&SingleByteDialect {
+            quote_char: Some(b'"'),
+            escape_char: Some(b'\\'),
+            has_quoted_line_breaks: true,
+            ..dialect(b';')
+        });
+    }
+
+    /// Escape char in unquoted values is a part of them (`C:\temp`)
+    #[test]
+    fn escape_inside_quotes() {
+        for field_separator in [b',', b'\t'] {
+            assert_round_trip(&SingleByteDialect {
+                quote_char: Some(b'"'),
+                escape_char: Some(b'\\'),
+                escape_scope: EscapeScope::InsideQuotes,
+                ..dialect(field_separator)
+            });
+        }
+    }
+
+    #[test]
+    fn record_terminators() {
+        assert_round_trip(&SingleByteDialect { record_terminator: RecordTerminator::Crlf, ..dialect(b',') });
+        assert_round_trip(&SingleByteDialect {
+            record_terminator: RecordTerminator::Byte(asv::RECORD_SEPARATOR),
+            ..dialect(asv::FIELD_SEPARATOR)
+        });
+    }
+
+    #[test]
+    fn field_separator_is_terminator() {
+        assert_round_trip(&SingleByteDialect { field_separator_is_terminator: true, ..dialect(b';') });
+    }
+
+    /// Escaped line breaks are never detected
+    #[test]
+    fn quoted_line_breaks() {
+        assert_round_trip(&SingleByteDialect {
+            quote_char: Some(b'"'),
+            has_quoted_line_breaks: true,
+            ..dialect(b',')
+        });
+    }
+}
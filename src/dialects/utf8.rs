@@ -0,0 +1,147 @@
+//! Applies `Utf8Policy` to converted cells. It's a part of
+//! `ControlByteGuard`, which gets every value byte and knows where
+//! cells and rows end (values may contain ASV separators, see
+//! `ControlBytePolicy::PassThrough`). Cells are buffered until they
+//! end and are checked as a whole, rows are buffered too, as they
+//! may be dropped.
+//!
+//! First row is the header if dialect has one: it's never dropped
+//! or counted, invalid bytes in it are replaced.
+
+use std::io::{Result, Write};
+use memchr::memchr;
+use crate::asv;
+use super::Utf8Policy;
+
+pub(crate) struct Utf8Check {
+    policy: Utf8Policy,
+    is_header: bool,
+    /// Value of the current cell, written by the guard
+    pub cell: Vec<u8>,
+    row: Vec<u8>,
+    column: usize,
+    is_row_dropped: bool,
+    affected_cells: Vec<usize>,
+}
+
+impl Utf8Check {
+    pub fn new(policy: Utf8Policy, has_header: bool) -> Self {
+        Self {
+            policy,
+            is_header: has_header,
+            cell: vec![],
+            row: vec![],
+            column: 0,
+            is_row_dropped: false,
+            affected_cells: vec![],
+        }
+    }
+
+    /// Number of affected cells per output column
+    pub fn affected_cells(&self) -> &[usize] {
+        &self.affected_cells
+    }
+
+    pub fn end_cell(&mut self) {
+        if std::str::from_utf8(&self.cell).is_err() {
+            self.apply_policy();
+        } else if self.policy == Utf8Policy::HexEscape && !self.is_header && memchr(b'\\', &self.cell).is_some() {
+            // backslashes of all cells are escaped, so escapes are unambiguous
+            self.cell = hex_escape(&self.cell);
+        }
+
+        self.row.extend_from_slice(&self.cell);
+        self.cell.clear();
+    }
+
+    pub fn separator(&mut self) {
+        self.row.push(asv::FIELD_SEPARATOR);
+        self.column += 1;
+    }
+
+    /// Writes row with terminator, unless it's dropped
+    pub fn end_row(&mut self, writer: &mut impl Write) -> Result<()> {
+        self.end_cell();
+        if !self.is_row_dropped {
+            self.row.push(asv::RECORD_SEPARATOR);
+            writer.write_all(&self.row)?;
+        }
+
+        self.row.clear();
+        self.column = 0;
+        self.is_header = false;
+        self.is_row_dropped = false;
+        Ok(())
+    }
+
+    #[cold]
+    fn apply_policy(&mut self) {
+        if self.is_header {
+            self.cell = String::from_utf8_lossy(&self.cell).into_owned().into_bytes();
+            return
+        }
+
+        if self.affected_cells.len() <= self.column {
+            self.affected_cells.resize(self.column + 1, 0);
+        }
+        self.affected_cells[self.column] += 1;
+
+        match self.policy {
+            Utf8Policy::PassThrough => unreachable!("cells are not checked"),
+            Utf8Policy::Replace => {
+                self.cell = String::from_utf8_lossy(&self.cell).into_owned().into_bytes();
+            }
+            Utf8Policy::DropCell => self.cell.clear(),
+            Utf8Policy::DropRow => self.is_row_dropped = true,
+            Utf8Policy::HexEscape => self.cell = hex_escape(&self.cell),
+        }
+    }
+}
+
+/// Every byte of invalid sequence and every `\` is written as `\xNN`
+fn hex_escape(mut value: &[u8]) -> Vec<u8> {
+    fn push_valid(escaped: &mut Vec<u8>, valid: &[u8]) {
+        for part in valid.split_inclusive(|c| *c == b'\\') {
+            match part.strip_suffix(b"\\") {
+                Some(part) => {
+                    escaped.extend_from_slice(part);
+                    escaped.extend_from_slice(b"\\x5C");
+                }
+                None => escaped.extend_from_slice(part),
+            }
+        }
+    }
+
+    let mut escaped = Vec::with_capacity(value.len() * 2);
+    while !value.is_empty() {
+        match std::str::from_utf8(value) {
+            Ok(valid) => {
+                push_valid(&mut escaped, valid.as_bytes());
+                break
+            }
+            Err(e) => {
+                let (valid, rest) = value.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                push_valid(&mut escaped, valid);
+                for c in &rest[..invalid_len] {
+                    escaped.extend_from_slice(format!("\\x{c:02X}").as_bytes());
+                }
+                value = &rest[invalid_len..];
+            }
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hex_escape;
+
+    #[test]
+    fn hex_escape_is_reversible() {
+        assert_eq!(hex_escape(b"caf\xe9"), b"caf\\xE9");
+        assert_eq!(hex_escape(b"\\xE9 \xff\\"), b"\\x5CxE9 \\xFF\\x5C");
+        assert_eq!(hex_escape(b"plain"), b"plain");
+    }
+}
@@ -0,0 +1,228 @@
+//! Character encoding detection and decoding to UTF-8 (`encoding`
+//! feature). Encoding is detected once by the first chunk of input:
+//! BOM (see `Bom`), UTF-16 without BOM by zero bytes, valid UTF-8,
+//! otherwise guessed by `chardetng`.
+//!
+//! Sniffer validates decoded input for anything but UTF-8, so headers
+//! are readable and separators of UTF-16 are found correctly.
+
+use std::cmp::min;
+use std::io::{Read, Result};
+use chardetng::EncodingDetector;
+use encoding_rs::{Decoder, Encoding, EUC_JP, EUC_KR, UTF_16BE, UTF_16LE, UTF_8};
+
+/// Bytes checked for UTF-16 zero bytes pattern
+const UTF16_SAMPLE_SIZE: usize = 4096;
+/// Zero bytes needed to detect UTF-16 in short input
+const MIN_UTF16_ZEROS: usize = 4;
+const BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// Detects encoding of input without BOM
+pub(crate) fn detect(head: &[u8]) -> &'static Encoding {
+    if let Some(encoding) = detect_utf16(&head[..min(head.len(), UTF16_SAMPLE_SIZE)]) {
+        return encoding
+    }
+
+    match std::str::from_utf8(head) {
+        Ok(_) => UTF_8,
+        // chunk may end in the middle of character
+        Err(e) if e.error_len().is_none() => UTF_8,
+        Err(_) => {
+            let mut detector = EncodingDetector::new();
+            detector.feed(head, false);
+            detector.guess(None, false)
+        }
+    }
+}
+
+/// ASCII characters of UTF-16 text (separators, line breaks, digits)
+/// have zero high byte, while other characters rarely have zero low
+/// byte: zeros are found in one byte position and almost never in the
+/// other one. Text in other encodings has no zeros at all.
+fn detect_utf16(sample: &[u8]) -> Option<&'static Encoding> {
+    let pairs = sample.len() / 2;
+    let even_zeros = sample.iter().step_by(2).filter(|c| **c == 0).count();
+    let odd_zeros = sample.iter().skip(1).step_by(2).filter(|c| **c == 0).count();
+    let is_asymmetric = |zeros: usize, other_zeros: usize| {
+        zeros >= MIN_UTF16_ZEROS && zeros > pairs / 32 && other_zeros <= zeros / 16
+    };
+
+    if is_asymmetric(odd_zeros, even_zeros) {
+        Some(UTF_16LE)
+    } else if is_asymmetric(even_zeros, odd_zeros) {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// Encoding can't be tokenized byte by byte, as ASCII bytes may be
+/// parts of other characters (UTF-16, Shift_JIS, Big5, ...)
+pub(crate) fn needs_decoding(encoding: &'static Encoding) -> bool {
+    !(encoding == UTF_8 || encoding.is_single_byte() || encoding == EUC_JP || encoding == EUC_KR)
+}
+
+/// Decodes chunks of stream (with BOM already skipped) into UTF-8
+pub(crate) struct Transcoder {
+    decoder: Decoder,
+    output: Vec<u8>,
+}
+
+impl Transcoder {
+    pub fn new(encoding: &'static Encoding) -> Self {
+        Self {
+            decoder: encoding.new_decoder_without_bom_handling(),
+            output: vec![],
+        }
+    }
+
+    /// Decoded chunk, incomplete character at the end is kept
+    /// until the next chunk, unless `last`
+    pub fn transcode(&mut self, chunk: &[u8], last: bool) -> &[u8] {
+        let max_len = self.decoder
+            .max_utf8_buffer_length(chunk.len())
+            .expect("Chunk is too large to decode");
+        self.output.resize(max_len, 0);
+
+        let (_, read, written, _) = self.decoder.decode_to_utf8(chunk, &mut self.output, last);
+        debug_assert_eq!(read, chunk.len());
+        self.output.truncate(written);
+        &self.output
+    }
+}
+
+/// Reader of decoded stream
+pub(crate) struct TranscodingReader<R: Read> {
+    reader: R,
+    transcoder: Transcoder,
+    input: Vec<u8>,
+    pos: usize,
+    is_eof: bool,
+}
+
+impl<R: Read> TranscodingReader<R> {
+    pub fn new(reader: R, encoding: &'static Encoding) -> Self {
+        Self {
+            reader,
+            transcoder: Transcoder::new(encoding),
+            input: vec![0; BUFFER_SIZE],
+            pos: 0,
+            is_eof: false,
+        }
+    }
+}
+
+impl<R: Read> Read for TranscodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let available = &self.transcoder.output[self.pos..];
+            if !available.is_empty() {
+                let size = min(available.len(), buf.len());
+                buf[..size].copy_from_slice(&available[..size]);
+                self.pos += size;
+                return Ok(size)
+            }
+            if self.is_eof {
+                return Ok(0)
+            }
+
+            let size = self.reader.read(&mut self.input)?;
+            self.is_eof = size == 0;
+            self.transcoder.transcode(&self.input[..size], self.is_eof);
+            self.pos = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{Encoding, KOI8_R, SHIFT_JIS, WINDOWS_1251};
+    use crate::{CsvSniffer, Dialect, NormalizeOptions};
+
+    /// Table with Cyrillic values
+    fn table() -> String {
+        let names = ["Иван Петров", "Мария Сидорова", "Ольга Кузнецова", "Сергей Смирнов"];
+        let mut src = "name,city,age\n".to_string();
+        for i in 0..40 {
+            src.push_str(&format!("{},Москва,{}\n", names[i % names.len()], 20 + i));
+        }
+        src
+    }
+
+    fn encode(src: &str, encoding: &'static Encoding) -> Vec<u8> {
+        encoding.encode(src).0.into_owned()
+    }
+
+    /// `encoding_rs` encodes UTF-16 as UTF-8
+    fn utf16(src: &str, bom: bool, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        let bom = bom.then_some(0xfeff);
+        bom.into_iter().chain(src.encode_utf16()).flat_map(to_bytes).collect()
+    }
+
+    /// The best dialect found in stream and in local file,
+    /// which must be the same
+    fn sniff(src: &[u8]) -> Dialect {
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process(&mut &src[..]);
+        let stream = sniffer.dialects().into_iter().max().expect("Dialect of stream");
+
+        let path = std::env::temp_dir().join(format!("brutal-csv-encoding-{}-{}.csv", std::process::id(), src.len()));
+        std::fs::write(&path, src).unwrap();
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let file = sniffer.dialects().into_iter().max().expect("Dialect of file");
+
+        assert_eq!(stream, file);
+        stream
+    }
+
+    /// Encoding is detected, header and `value` are decoded
+    fn assert_detected<const N: usize>(src: &[u8], encoding: &str, header: &[&str; N], value: &str) {
+        let dialect = sniff(src);
+        let Dialect::SingleByte(single_byte) = &dialect else {
+            panic!("Single-byte dialect is expected for {encoding}")
+        };
+        assert_eq!(single_byte.encoding, Some(encoding));
+        assert_eq!(single_byte.field_separator, b',', "{encoding}");
+        assert_eq!(single_byte.header.as_deref(), Some(&header.map(str::to_string)[..]), "{encoding}");
+
+        let options = NormalizeOptions { transcode_to_utf8: true, ..Default::default() };
+        let mut asv = vec![];
+        dialect.to_asv_with_options(src, &mut asv, &options).unwrap();
+        assert!(String::from_utf8(asv).unwrap().contains(value), "{encoding}");
+    }
+
+    #[test]
+    fn stream_and_file_have_same_encoding() {
+        let src = encode(&table(), WINDOWS_1251);
+        assert_eq!(sniff(&src).encoding(), Some("windows-1251"));
+    }
+
+    #[test]
+    fn cyrillic_single_byte() {
+        let header = ["name", "city", "age"];
+        assert_detected(&encode(&table(), WINDOWS_1251), "windows-1251", &header, "Ольга Кузнецова");
+        // superset of KOI8-R, Russian text is decoded the same
+        assert_detected(&encode(&table(), KOI8_R), "KOI8-U", &header, "Ольга Кузнецова");
+    }
+
+    #[test]
+    fn shift_jis() {
+        let names = ["山田太郎", "佐藤花子", "鈴木一郎", "高橋美咲"];
+        let mut src = "名前,都市,年齢\n".to_string();
+        for i in 0..40 {
+            src.push_str(&format!("{},東京,{}\n", names[i % names.len()], 20 + i));
+        }
+        assert_detected(&encode(&src, SHIFT_JIS), "Shift_JIS", &["名前", "都市", "年齢"], "鈴木一郎");
+    }
+
+    #[test]
+    fn utf16_with_and_without_bom() {
+        let header = ["name", "city", "age"];
+        for bom in [true, false] {
+            assert_detected(&utf16(&table(), bom, u16::to_le_bytes), "UTF-16LE", &header, "Ольга Кузнецова");
+            assert_detected(&utf16(&table(), bom, u16::to_be_bytes), "UTF-16BE", &header, "Ольга Кузнецова");
+        }
+    }
+}
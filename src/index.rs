@@ -0,0 +1,260 @@
+//! Sparse index of record offsets in the source file, built during
+//! conversion (`NormalizeOptions::record_index`). It allows to read
+//! any record with `SingleByteDialect::reader_at` without parsing
+//! whole file before it.
+//!
+//! Sidecar file format (all numbers are little-endian `u64`):
+//!   - magic `BCSVIDX1`
+//!   - `every`, `records`, number of offsets
+//!   - offsets
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"BCSVIDX1";
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RecordIndex {
+    /// Offset of every `every`-th record is stored
+    pub every: u64,
+    /// Number of data records (header row is not counted)
+    pub records: u64,
+    /// Byte offsets of records `0, every, 2 * every, ...`,
+    /// each one is a row start outside of quotes
+    pub offsets: Vec<u64>,
+}
+
+impl RecordIndex {
+    /// Conventional sidecar location: `<path>.idx`
+    pub fn sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+        let mut sidecar = path.as_ref().as_os_str().to_owned();
+        sidecar.push(".idx");
+        PathBuf::from(sidecar)
+    }
+
+    /// Nearest indexed record at or before `record`: returns its
+    /// offset and number of records to skip after it.
+    /// `None` if there is no such record.
+    pub fn locate(&self, record: u64) -> Option<(u64, u64)> {
+        if record >= self.records || self.every == 0 {
+            return None
+        }
+
+        let i = record / self.every;
+        let offset = *self.offsets.get(i as usize)?;
+        Some((offset, record % self.every))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        for value in [self.every, self.records, self.offsets.len() as u64] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for offset in &self.offsets {
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from(mut reader: impl Read) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a record index file"))
+        }
+
+        let every = read_u64(&mut reader)?;
+        let records = read_u64(&mut reader)?;
+        let len = read_u64(&mut reader)?;
+        if every == 0 || len != records.div_ceil(every) {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupted record index file"))
+        }
+
+        let offsets = (0..len)
+            .map(|_| read_u64(&mut reader))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { every, records, offsets })
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+/// Collects offsets of row starts as rows end
+#[derive(Clone, Debug)]
+pub(crate) struct IndexBuilder {
+    every: u64,
+    header_rows: u64,
+    /// Offset of the first row (after BOM)
+    start: u64,
+    rows: u64,
+    offsets: Vec<u64>,
+}
+
+impl IndexBuilder {
+    /// `header_rows` rows at file start are not records,
+    /// reported offsets are relative to `start`
+    pub fn new(every: u64, header_rows: u64, start: u64) -> Self {
+        assert!(every > 0, "Record index step must be positive");
+        Self {
+            every,
+            header_rows,
+            start,
+            rows: 0,
+            offsets: vec![],
+        }
+    }
+
+    /// Builder which keeps start of every row after the first one
+    #[cfg(feature = "parallel")]
+    pub fn row_starts() -> Self {
+        Self::new(1, 0, 0)
+    }
+
+    /// Offsets collected by `row_starts` builder
+    #[cfg(feature = "parallel")]
+    pub fn into_row_starts(self) -> Vec<u64> {
+        self.offsets
+    }
+
+    /// `next_row_offset` is where the following row starts
+    #[inline]
+    pub fn end_row(&mut self, next_row_offset: u64) {
+        self.rows += 1;
+        if self.rows < self.header_rows {
+            return
+        }
+
+        let record = self.rows - self.header_rows;
+        if record % self.every == 0 {
+            self.offsets.push(self.start + next_row_offset);
+        }
+    }
+
+    pub fn finish(mut self) -> RecordIndex {
+        // first record starts the file
+        if self.header_rows == 0 {
+            self.offsets.insert(0, self.start);
+        }
+
+        // the last row end is followed by no record
+        let records = self.rows.saturating_sub(self.header_rows);
+        self.offsets.truncate(records.div_ceil(self.every) as usize);
+
+        RecordIndex {
+            every: self.every,
+            records,
+            offsets: self.offsets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind};
+    use crate::{CsvSniffer, Dialect, NormalizeOptions};
+    use crate::dialects::KeyValueDialect;
+    use super::{IndexBuilder, RecordIndex};
+
+    #[test]
+    fn builder_skips_header_rows() {
+        // BOM of 3 bytes, header and 4 records of 10 bytes
+        let mut builder = IndexBuilder::new(2, 1, 3);
+        [10, 20, 30, 40, 50].into_iter().for_each(|offset| builder.end_row(offset));
+        assert_eq!(builder.finish(), RecordIndex { every: 2, records: 4, offsets: vec![13, 33] });
+
+        let mut builder = IndexBuilder::new(3, 0, 0);
+        [5, 10, 15, 20].into_iter().for_each(|offset| builder.end_row(offset));
+        assert_eq!(builder.finish(), RecordIndex { every: 3, records: 4, offsets: vec![0, 15] });
+    }
+
+    #[test]
+    fn locate() {
+        let index = RecordIndex { every: 2, records: 4, offsets: vec![13, 33] };
+        assert_eq!(index.locate(0), Some((13, 0)));
+        assert_eq!(index.locate(1), Some((13, 1)));
+        assert_eq!(index.locate(3), Some((33, 1)));
+        assert_eq!(index.locate(4), None);
+        assert_eq!(RecordIndex::default().locate(0), None);
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let index = RecordIndex { every: 2, records: 3, offsets: vec![13, 33] };
+        let mut sidecar = vec![];
+        index.write_to(&mut sidecar).unwrap();
+        assert_eq!(RecordIndex::read_from(&sidecar[..]).unwrap(), index);
+
+        // number of offsets doesn't match number of records
+        sidecar[16] = 5;
+        assert_eq!(RecordIndex::read_from(&sidecar[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reader_at_indexed_records() {
+        let mut src = b"id,name,note\n".to_vec();
+        for i in 0..50 {
+            src.extend_from_slice(format!("{i},name{i},\"note\n{i}\"\n").as_bytes());
+        }
+
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process(&mut &src[..]);
+        let dialect = sniffer.dialects().into_iter().max().unwrap();
+        let options = NormalizeOptions { record_index: Some(7), ..Default::default() };
+        let index = dialect.to_asv_with_options(&src[..], vec![], &options).unwrap().record_index.unwrap();
+        assert_eq!((index.records, index.offsets.len()), (50, 8));
+
+        let Dialect::SingleByte(dialect) = dialect else {
+            panic!("Single-byte dialect is expected")
+        };
+        let expected: Vec<_> = dialect.reader(&src[..]).unwrap().records().collect::<Result<_, _>>().unwrap();
+        for record in 0..50 {
+            let mut reader = dialect.reader_at(Cursor::new(&src), &index, record).unwrap();
+            let actual: Vec<_> = reader.records().collect::<Result<_, _>>().unwrap();
+            assert_eq!(actual, expected[record as usize..], "from record {record}");
+        }
+    }
+
+    #[test]
+    fn refused_for_key_value_dialect() {
+        let dialect = Dialect::KeyValue(KeyValueDialect { field_separator: b':', ..Default::default() });
+        let options = NormalizeOptions { record_index: Some(1), ..Default::default() };
+        let error = dialect.to_asv_with_options(&b"login:password\n"[..], vec![], &options).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn refused_for_compressed_input() {
+        use std::io::Write;
+
+        let src = b"id,name\n1,ann\n2,bob\n3,carl\n4,dan\n5,eve\n";
+        let mut sniffer = CsvSniffer::new(None);
+        sniffer.process(&mut &src[..]);
+        let dialect = sniffer.dialects().into_iter().max().unwrap();
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(src).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let options = NormalizeOptions { record_index: Some(1), ..Default::default() };
+        let error = dialect.to_asv_with_options(&compressed[..], vec![], &options).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+}
@@ -1,13 +1,49 @@
 #![doc = include_str!("../README.md")]
 
-use std::io::Read;
-use crate::dialects::{Dialect, DialectGroupValidator, KeyValueDialectValidator, SingleByteDialectValidator};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use crate::dialects::{DialectGroupValidator, KeyValueDialectValidator, LineStarts, RestoredDialectValidator, SingleByteDialectValidator, MAX_LINE_PREFIX_LEN};
+pub use crate::dialects::{Dialect, SingleByteDialect, SingleByteDialectReader, SingleByteDialectRecords, SingleByteDialectWriter, RecordTerminator, Quoting, StrayQuotes, EscapeScope, BlankLines, KeyValueDialect, RestoredDialect};
+pub use crate::dialects::{NormalizeOptions, NormalizeReport, QuarantineSink, ControlBytePolicy, Utf8Policy, Tolerance, MalformedRow};
+pub use crate::decompress::Compression;
+pub use crate::bom::Bom;
 
+mod bom;
+mod decompress;
 mod dialects;
+#[cfg(feature = "encoding")]
+mod encoding;
+mod mapped;
+pub mod asv;
+pub mod index;
+#[cfg(any(feature = "zip", feature = "tar"))]
+pub mod archive;
+
+const CHUNK_SIZE: usize = 1024 * 1024; // 1 MiB
 
 #[derive(Default)]
 pub struct CsvSniffer {
-    validators: Vec<Box<dyn DialectGroupValidator>>
+    validators: Vec<Box<dyn DialectGroupValidator + Send>>,
+    /// Validators of `Dialect::Restored`, which is the last resort:
+    /// memory-mapped file is checked by them in a second pass, only
+    /// if no other dialect fits, stream is checked in the same pass
+    fallback: Vec<Box<dyn DialectGroupValidator + Send>>,
+    /// Found by the first pass over memory-mapped file
+    dialects: Vec<Dialect>,
+    /// Last bytes of validated input, see `LineStarts`
+    tail: Option<Vec<u8>>,
+    #[cfg(feature = "parallel")]
+    pool: Option<rayon::ThreadPool>,
+    /// First chunk is processed
+    has_started: bool,
+    bom: Option<Bom>,
+    /// Detected by the first chunk
+    #[cfg(feature = "encoding")]
+    encoding: Option<&'static encoding_rs::Encoding>,
+    /// Decodes input for validators, if it's not UTF-8
+    #[cfg(feature = "encoding")]
+    transcoder: Option<encoding::Transcoder>,
 }
 
 impl CsvSniffer {
@@ -20,27 +56,118 @@ impl CsvSniffer {
 
         validators.extend(SingleByteDialectValidator::make(has_headers)
             .into_iter()
-            .map(|x| Box::new(x) as Box<dyn DialectGroupValidator>)
+            .map(|x| Box::new(x) as Box<dyn DialectGroupValidator + Send>)
         );
 
         validators.extend(KeyValueDialectValidator::make()
             .into_iter()
-            .map(|x| Box::new(x) as Box<dyn DialectGroupValidator>)
+            .map(|x| Box::new(x) as Box<dyn DialectGroupValidator + Send>)
         );
 
+        let fallback = RestoredDialectValidator::make(has_headers)
+            .into_iter()
+            .map(|x| Box::new(x) as Box<dyn DialectGroupValidator + Send>)
+            .collect();
+
         Self {
-            validators
+            validators,
+            fallback,
+            dialects: vec![],
+            tail: None,
+            #[cfg(feature = "parallel")]
+            pool: None,
+            has_started: false,
+            bom: None,
+            #[cfg(feature = "encoding")]
+            encoding: None,
+            #[cfg(feature = "encoding")]
+            transcoder: None,
+        }
+    }
+
+    /// Splits validators across `threads` worker threads
+    /// (0 = one per CPU), each chunk is still read once.
+    /// Detected dialects are the same as in sequential mode.
+    #[cfg(feature = "parallel")]
+    pub fn with_threads(mut self, threads: usize) -> Result<Self, rayon::ThreadPoolBuildError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+
+        self.pool = Some(pool);
+        Ok(self)
+    }
+
+    /// Dialects survive malformed records up to `tolerance`,
+    /// which are reported as `SingleByteDialect::malformed_rows`
+    pub fn with_tolerance(mut self, tolerance: Tolerance) -> Self {
+        for validator in self.validators.iter_mut().chain(&mut self.fallback) {
+            validator.set_tolerance(tolerance);
         }
+        self
     }
 
     /// Validates file against each CSV dialect.
+    /// Compressed input is decompressed (see `Compression`).
     ///
     /// You must pass whole file into it, otherwise behaviour is undefined.
     pub fn process<T: Read>(&mut self, reader: &mut T) {
-        let mut buffer = [b'0'; 1024*1024]; // 1 MiB chunks
+        self.try_process(reader).unwrap()
+    }
+
+    /// Same as `process`, but local files are memory-mapped instead of
+    /// being copied through `Read`. Special files (pipes, devices) are
+    /// read as usual.
+    ///
+    /// File must not be modified while it's processed.
+    pub fn process_path(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let Some(map) = mapped::map(&file) else {
+            return self.try_process(&mut file)
+        };
+        if decompress::is_compressed(&map) {
+            return self.try_process(&mut &map[..])
+        }
+
+        self.process_slice(&map);
+        if self.fallback.is_empty() {
+            return Ok(())
+        }
+
+        self.dialects = self.finalize();
+        if self.dialects.is_empty() {
+            // second pass starts from scratch, as the first one did
+            self.has_started = false;
+            self.tail = None;
+            #[cfg(feature = "encoding")]
+            {
+                self.transcoder = None;
+            }
+            self.validators = std::mem::take(&mut self.fallback);
+            self.process_slice(&map);
+        }
+
+        Ok(())
+    }
+
+    fn process_slice(&mut self, src: &[u8]) {
+        for chunk in src.chunks(CHUNK_SIZE) {
+            self.process_chunk(chunk);
+            if self.validators.is_empty() {
+                break
+            }
+        }
+    }
+
+    pub(crate) fn try_process<T: Read>(&mut self, reader: &mut T) -> io::Result<()> {
+        // stream can't be read twice
+        let fallback = std::mem::take(&mut self.fallback);
+        self.validators.extend(fallback);
+        let mut reader = decompress::decoder(reader)?;
+        let mut buffer = vec![b'0'; CHUNK_SIZE];
 
         loop {
-            let chunk_size = reader.read(&mut buffer).unwrap();
+            let chunk_size = read_chunk(&mut reader, &mut buffer)?;
             if chunk_size == 0 {
                 break
             }
@@ -50,10 +177,76 @@ impl CsvSniffer {
                 break
             }
         }
+
+        Ok(())
     }
 
     #[inline]
-    fn process_chunk(&mut self, chunk: &[u8]) {
+    fn process_chunk(&mut self, mut chunk: &[u8]) {
+        if !self.has_started {
+            self.has_started = true;
+            self.bom = Bom::detect(chunk);
+            chunk = &chunk[bom::len(self.bom)..];
+
+            #[cfg(feature = "encoding")]
+            self.detect_encoding(chunk);
+        }
+
+        #[cfg(feature = "encoding")]
+        if let Some(mut transcoder) = self.transcoder.take() {
+            self.validate_chunk(transcoder.transcode(chunk, false));
+            self.transcoder = Some(transcoder);
+            return
+        }
+
+        self.validate_chunk(chunk)
+    }
+
+    #[cfg(feature = "encoding")]
+    fn detect_encoding(&mut self, head: &[u8]) {
+        let encoding = match self.bom {
+            Some(bom) => bom.encoding(),
+            None => encoding::detect(head),
+        };
+
+        self.encoding = Some(encoding);
+        if encoding != encoding_rs::UTF_8 {
+            self.transcoder = Some(encoding::Transcoder::new(encoding));
+        }
+    }
+
+    #[inline]
+    fn validate_chunk(&mut self, chunk: &[u8]) {
+        let lines = LineStarts::new(chunk, self.tail.as_deref());
+        let spawned: Vec<_> = self.validators
+            .iter_mut()
+            .flat_map(|c| c.spawn(&lines))
+            .collect();
+        self.validators.extend(spawned);
+
+        if !chunk.is_empty() {
+            let tail = self.tail.get_or_insert_with(Vec::new);
+            tail.extend_from_slice(&chunk[chunk.len().saturating_sub(MAX_LINE_PREFIX_LEN)..]);
+            tail.drain(..tail.len().saturating_sub(MAX_LINE_PREFIX_LEN));
+        }
+
+        #[cfg(feature = "parallel")]
+        if let Some(pool) = &self.pool {
+            use rayon::prelude::*;
+
+            let validators = &mut self.validators;
+            let is_valid: Vec<bool> = pool.install(|| validators
+                .par_iter_mut()
+                .map(|c| c.try_process_chunk(chunk).is_ok())
+                .collect()
+            );
+
+            // keep order, so results are same as in sequential mode
+            let mut is_valid = is_valid.into_iter();
+            self.validators.retain(|_| is_valid.next().unwrap());
+            return
+        }
+
         self.validators.retain_mut(|c| {
             let res = c.try_process_chunk(chunk);
             // if let Err(e) = &res {
@@ -65,13 +258,85 @@ impl CsvSniffer {
     }
 
     /// Returns valid dialects for processed file.
-    pub fn dialects(self) -> Vec<Dialect> {
-        self.validators
+    /// `Dialect::Restored` is returned only if no other dialect fits.
+    pub fn dialects(mut self) -> Vec<Dialect> {
+        let mut dialects = std::mem::take(&mut self.dialects);
+        dialects.extend(self.finalize());
+        if dialects.iter().any(|dialect| !matches!(dialect, Dialect::Restored(_))) {
+            dialects.retain(|dialect| !matches!(dialect, Dialect::Restored(_)));
+        }
+
+        for dialect in &mut dialects {
+            dialect.set_bom(self.bom);
+        }
+
+        #[cfg(feature = "encoding")]
+        if let Some(encoding) = self.encoding {
+            for dialect in &mut dialects {
+                dialect.set_encoding(encoding.name());
+            }
+        }
+
+        dialects
+    }
+
+    /// Dialects of remaining validators, which are dropped
+    fn finalize(&mut self) -> Vec<Dialect> {
+        #[cfg(feature = "encoding")]
+        if let Some(mut transcoder) = self.transcoder.take() {
+            self.validate_chunk(transcoder.transcode(&[], true));
+        }
+
+        std::mem::take(&mut self.validators)
             .into_iter()
-            .filter_map(|mut x| x.finalize())
+            .flat_map(|mut x| x.finalize())
             .collect()
     }
 }
 
+/// Fills `buffer` unless input ends: BOM and encoding are detected by
+/// the first chunk, and decoders (and pipes) return short reads, so
+/// stream is split into the same chunks as memory-mapped file
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buffer.len() {
+        match reader.read(&mut buffer[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use super::*;
 
+    /// Quoted, escaped, commented and blank lines, so that validators
+    /// fail at different chunks and some are spawned on the way
+    #[test]
+    fn parallel_sniffing_is_sequential() {
+        let mut src = String::from("id;name;note\r\n");
+        for i in 1..=40 {
+            match i % 10 {
+                3 => src.push_str("# comment\r\n"),
+                5 => src.push_str("\r\n"),
+                7 => src.push_str(&format!("{i};\"x;y\";a\\;b\r\n")),
+                _ => src.push_str(&format!("{i};n{i};'q'\r\n")),
+            }
+        }
 
+        let dialects = |sniffer: CsvSniffer| {
+            let mut sniffer = sniffer;
+            for chunk in src.as_bytes().chunks(64) {
+                sniffer.process_chunk(chunk);
+            }
+            sniffer.dialects()
+        };
+        let sequential = dialects(CsvSniffer::new(None));
+        assert!(!sequential.is_empty());
+        assert_eq!(dialects(CsvSniffer::new(None).with_threads(4).unwrap()), sequential);
+    }
+}
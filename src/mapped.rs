@@ -0,0 +1,22 @@
+//! Memory mapping of local files, so they are processed without copying.
+
+use std::fs::File;
+use memmap2::Mmap;
+
+/// Maps regular non-empty file. Returns `None` for special
+/// files (pipes, devices, procfs), they should be read instead.
+pub(crate) fn map(file: &File) -> Option<Mmap> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None
+    }
+
+    // Safety: file must not be modified while it's mapped,
+    // that's documented for all public functions using it
+    let map = unsafe { Mmap::map(file) }.ok()?;
+
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+
+    Some(map)
+}